@@ -0,0 +1,218 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+pub type MemberId = String;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TopicPartition {
+    pub topic: String,
+    pub partition: u32
+}
+
+impl TopicPartition {
+    pub fn new<T: Into<String>>(topic: T, partition: u32) -> TopicPartition {
+        TopicPartition { topic: topic.into(), partition }
+    }
+}
+
+// The partitions each member of a group consumes
+pub type Assignment = BTreeMap<MemberId, Vec<TopicPartition>>;
+
+// Decides which member of a group consumes each partition. `subscriptions` holds the topics of
+// every member, `partitions` the number of partitions of every topic, and `previous` the
+// assignment being replaced. Every partition of a subscribed topic goes to exactly one member
+// subscribed to the topic, and every member gets an entry, even if it has nothing to consume.
+pub trait AssignmentStrategy: Send + Sync {
+    fn assign(&self, subscriptions: &BTreeMap<MemberId, Vec<String>>, partitions: &BTreeMap<String, u32>, previous: &Assignment) -> Assignment;
+}
+
+// Splits each topic into contiguous runs of partitions, one for each subscribed member in order
+// of id. Members earlier in the order take one extra when the partitions don't divide evenly.
+pub struct RangeStrategy;
+
+impl AssignmentStrategy for RangeStrategy {
+    fn assign(&self, subscriptions: &BTreeMap<MemberId, Vec<String>>, partitions: &BTreeMap<String, u32>, previous: &Assignment) -> Assignment {
+        let mut assignment = empty_assignment(subscriptions);
+
+        for (topic, &num_partitions) in partitions {
+            let members: Vec<&MemberId> = subscriptions.keys().filter(|member| is_subscribed(subscriptions, member, topic)).collect();
+            if members.is_empty() {
+                continue;
+            }
+
+            let per_member = num_partitions / members.len() as u32;
+            let num_extra = num_partitions % members.len() as u32;
+
+            let mut start = 0;
+            for (i, member) in members.into_iter().enumerate() {
+                let end = start + per_member + if (i as u32) < num_extra { 1 } else { 0 };
+                let owned = assignment.entry(member.clone()).or_default();
+                owned.extend((start..end).map(|partition| TopicPartition::new(topic.as_str(), partition)));
+                start = end;
+            }
+        }
+
+        assignment
+    }
+}
+
+// Deals every partition out in turn to the members in order of id, passing over members not
+// subscribed to the partition's topic
+pub struct RoundRobinStrategy;
+
+impl AssignmentStrategy for RoundRobinStrategy {
+    fn assign(&self, subscriptions: &BTreeMap<MemberId, Vec<String>>, partitions: &BTreeMap<String, u32>, previous: &Assignment) -> Assignment {
+        let mut assignment = empty_assignment(subscriptions);
+        let members: Vec<&MemberId> = subscriptions.keys().collect();
+
+        let mut next = 0;
+        for partition in subscribed_partitions(subscriptions, partitions) {
+            for i in 0..members.len() {
+                let member = members[(next + i) % members.len()];
+                if is_subscribed(subscriptions, member, &partition.topic) {
+                    assignment.entry(member.clone()).or_default().push(partition);
+                    next = (next + i + 1) % members.len();
+                    break;
+                }
+            }
+        }
+
+        assignment
+    }
+}
+
+// Keeps as much of the previous assignment as it can while still balancing the partitions across
+// the members, so a rebalance moves as few partitions as possible. The balance is exact when every
+// member subscribes to the same topics.
+pub struct StickyStrategy;
+
+impl AssignmentStrategy for StickyStrategy {
+    fn assign(&self, subscriptions: &BTreeMap<MemberId, Vec<String>>, partitions: &BTreeMap<String, u32>, previous: &Assignment) -> Assignment {
+        let mut assignment = empty_assignment(subscriptions);
+        if subscriptions.is_empty() {
+            return assignment;
+        }
+
+        let mut unassigned: BTreeSet<TopicPartition> = subscribed_partitions(subscriptions, partitions).into_iter().collect();
+        let quota = unassigned.len() / subscriptions.len();
+        let mut num_extra = unassigned.len() % subscriptions.len();
+
+        // The members holding the most keep theirs first, as they'd otherwise lose the most
+        let mut kept: Vec<(&MemberId, Vec<&TopicPartition>)> = subscriptions.keys()
+            .map(|member| {
+                let owned = previous.get(member).map(|owned| owned.as_slice()).unwrap_or(&[]);
+                let still_valid = owned.iter().filter(|partition| is_subscribed(subscriptions, member, &partition.topic) && unassigned.contains(partition));
+                (member, still_valid.collect())
+            })
+            .collect();
+        kept.sort_by(|a, b| b.1.len().cmp(&a.1.len()).then(a.0.cmp(b.0)));
+
+        for (member, owned) in kept {
+            let mut limit = quota;
+            if num_extra > 0 && owned.len() > quota {
+                limit += 1;
+                num_extra -= 1;
+            }
+
+            let entry = assignment.entry(member.clone()).or_default();
+            for partition in owned {
+                if entry.len() < limit && unassigned.remove(partition) {
+                    entry.push(partition.clone());
+                }
+            }
+        }
+
+        for partition in unassigned {
+            let least_loaded = subscriptions.keys()
+                .filter(|member| is_subscribed(subscriptions, member, &partition.topic))
+                .min_by_key(|member| (assignment.get(*member).map(|owned| owned.len()).unwrap_or(0), *member))
+                .cloned();
+
+            if let Some(member) = least_loaded {
+                assignment.entry(member).or_default().push(partition);
+            }
+        }
+
+        for owned in assignment.values_mut() {
+            owned.sort();
+        }
+        assignment
+    }
+}
+
+fn empty_assignment(subscriptions: &BTreeMap<MemberId, Vec<String>>) -> Assignment {
+    subscriptions.keys().map(|member| (member.clone(), Vec::new())).collect()
+}
+
+fn is_subscribed(subscriptions: &BTreeMap<MemberId, Vec<String>>, member: &str, topic: &str) -> bool {
+    subscriptions.get(member).map(|topics| topics.iter().any(|t| t == topic)).unwrap_or(false)
+}
+
+// Every partition of the topics at least one member subscribes to, in order
+fn subscribed_partitions(subscriptions: &BTreeMap<MemberId, Vec<String>>, partitions: &BTreeMap<String, u32>) -> Vec<TopicPartition> {
+    partitions.iter()
+        .filter(|&(topic, _)| subscriptions.values().any(|topics| topics.contains(topic)))
+        .flat_map(|(topic, &num_partitions)| (0..num_partitions).map(move |partition| TopicPartition::new(topic.as_str(), partition)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subscriptions(members: &[(&str, &[&str])]) -> BTreeMap<MemberId, Vec<String>> {
+        members.iter().map(|&(member, topics)| (member.to_string(), topics.iter().map(|t| t.to_string()).collect())).collect()
+    }
+
+    fn partitions(topics: &[(&str, u32)]) -> BTreeMap<String, u32> {
+        topics.iter().map(|&(topic, n)| (topic.to_string(), n)).collect()
+    }
+
+    fn owned(assignment: &Assignment, member: &str) -> Vec<(String, u32)> {
+        assignment[member].iter().map(|tp| (tp.topic.clone(), tp.partition)).collect()
+    }
+
+    fn tps(partitions: &[(&str, u32)]) -> Vec<(String, u32)> {
+        partitions.iter().map(|&(topic, partition)| (topic.to_string(), partition)).collect()
+    }
+
+    #[test]
+    fn test_range() {
+        let subs = subscriptions(&[("a", &["foo", "bar"]), ("b", &["foo", "bar"]), ("c", &["bar"])]);
+        let assignment = RangeStrategy.assign(&subs, &partitions(&[("foo", 3), ("bar", 2), ("baz", 4)]), &Assignment::new());
+
+        assert_eq!(owned(&assignment, "a"), tps(&[("bar", 0), ("foo", 0), ("foo", 1)]));
+        assert_eq!(owned(&assignment, "b"), tps(&[("bar", 1), ("foo", 2)]));
+        assert_eq!(owned(&assignment, "c"), tps(&[]));
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let subs = subscriptions(&[("a", &["foo", "bar"]), ("b", &["foo"]), ("c", &["foo", "bar"])]);
+        let assignment = RoundRobinStrategy.assign(&subs, &partitions(&[("foo", 3), ("bar", 2)]), &Assignment::new());
+
+        assert_eq!(owned(&assignment, "a"), tps(&[("bar", 0), ("foo", 0)]));
+        assert_eq!(owned(&assignment, "b"), tps(&[("foo", 1)]));
+        assert_eq!(owned(&assignment, "c"), tps(&[("bar", 1), ("foo", 2)]));
+    }
+
+    #[test]
+    fn test_sticky() {
+        let topics = partitions(&[("foo", 6)]);
+        let subs = subscriptions(&[("a", &["foo"]), ("b", &["foo"])]);
+        let first = StickyStrategy.assign(&subs, &topics, &Assignment::new());
+        assert_eq!(owned(&first, "a"), tps(&[("foo", 0), ("foo", 2), ("foo", 4)]));
+        assert_eq!(owned(&first, "b"), tps(&[("foo", 1), ("foo", 3), ("foo", 5)]));
+
+        // The new member takes one partition from each, and nothing else moves
+        let subs = subscriptions(&[("a", &["foo"]), ("b", &["foo"]), ("c", &["foo"])]);
+        let second = StickyStrategy.assign(&subs, &topics, &first);
+        assert_eq!(owned(&second, "a"), tps(&[("foo", 0), ("foo", 2)]));
+        assert_eq!(owned(&second, "b"), tps(&[("foo", 1), ("foo", 3)]));
+        assert_eq!(owned(&second, "c"), tps(&[("foo", 4), ("foo", 5)]));
+
+        let subs = subscriptions(&[("b", &["foo"]), ("c", &["foo"])]);
+        let third = StickyStrategy.assign(&subs, &topics, &second);
+        assert_eq!(owned(&third, "b"), tps(&[("foo", 0), ("foo", 1), ("foo", 3)]));
+        assert_eq!(owned(&third, "c"), tps(&[("foo", 2), ("foo", 4), ("foo", 5)]));
+    }
+}
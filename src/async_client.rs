@@ -0,0 +1,128 @@
+use std::pin::Pin;
+use std::sync::mpsc;
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::Duration;
+
+use futures::channel::{mpsc as stream_channel, oneshot};
+use futures::executor;
+use futures::{Future, FutureExt, SinkExt, Stream};
+
+use consumer::Consumer;
+use error::{Error, Result};
+use producer::Producer;
+use record::{Record, RecordMetadata};
+use segment::{Message, Offset};
+
+// Messages an async consumer reads ahead of the stream
+const CONSUMER_BUFFER: usize = 64;
+
+// How long an async consumer's thread waits for new messages before checking the stream is still wanted
+const CONSUMER_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// Sends records from async code without blocking the executor. The writes, and any syncs, are
+// done by a thread of the producer's own, in the order the records were sent.
+pub struct AsyncProducer {
+    requests: mpsc::Sender<(Record, oneshot::Sender<Result<RecordMetadata>>)>
+}
+
+impl AsyncProducer {
+    pub fn new(mut producer: Producer) -> AsyncProducer {
+        let (requests, received) = mpsc::channel::<(Record, oneshot::Sender<Result<RecordMetadata>>)>();
+
+        // Runs until the async producer is dropped, finishing the records already sent
+        thread::spawn(move || {
+            for (record, sent) in received {
+                let _ = sent.send(producer.send_record(&record));
+            }
+        });
+
+        AsyncProducer { requests }
+    }
+
+    // Appends the message, resolving to its offset once the producer's acks are satisfied
+    pub fn send(&self, message: &[u8]) -> impl Future<Output = Result<Offset>> {
+        self.send_record(Record::new(message)).map(|sent| sent.map(|metadata| metadata.offset))
+    }
+
+    // Appends the record, resolving to the partition and offset it was written at
+    pub fn send_record(&self, record: Record) -> impl Future<Output = Result<RecordMetadata>> {
+        let (sent, result) = oneshot::channel();
+        let _ = self.requests.send((record, sent));
+
+        result.map(|result| result.unwrap_or(Err(Error::Disconnected)))
+    }
+}
+
+// Streams the messages of a partition to async code. A thread of the consumer's own reads ahead,
+// waiting for new messages once caught up, and stops when the stream is dropped or a read fails.
+pub struct AsyncConsumer {
+    messages: stream_channel::Receiver<Result<Message>>
+}
+
+impl AsyncConsumer {
+    pub fn new(mut consumer: Consumer) -> AsyncConsumer {
+        let (mut sender, messages) = stream_channel::channel(CONSUMER_BUFFER);
+
+        thread::spawn(move || {
+            while !sender.is_closed() {
+                let message = match consumer.poll_timeout(CONSUMER_POLL_INTERVAL) {
+                    Ok(Some(message)) => Ok(message),
+                    Ok(None) => continue,
+                    Err(e) => Err(e),
+                };
+
+                let failed = message.is_err();
+                if executor::block_on(sender.send(message)).is_err() || failed {
+                    break;
+                }
+            }
+        });
+
+        AsyncConsumer { messages }
+    }
+}
+
+// Yields messages rather than bare records, so their offsets can be tracked and committed
+impl Stream for AsyncConsumer {
+    type Item = Result<Message>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Message>>> {
+        Pin::new(&mut self.messages).poll_next(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::Path;
+
+    use futures::StreamExt;
+    use futures::future;
+
+    use kafka::Kafka;
+
+    #[test]
+    fn test_send_and_stream() {
+        let path = Path::new("./test_data/test_async_send_and_stream");
+        fs::remove_dir_all(path);
+        let kafka = Kafka::new(path).unwrap();
+
+        let producer = AsyncProducer::new(kafka.producer("foo").unwrap());
+        let sends: Vec<_> = (0..5u8).map(|i| producer.send(&[i])).collect();
+        let offsets: Vec<u64> = executor::block_on(future::join_all(sends)).into_iter().map(|sent| sent.unwrap()).collect();
+        assert_eq!(offsets, vec![0, 1, 2, 3, 4]);
+
+        let consumer = AsyncConsumer::new(kafka.consumer("foo", 0).unwrap());
+        let mut messages = consumer.map(|message| message.unwrap());
+        let first: Vec<Message> = executor::block_on((&mut messages).take(5).collect());
+        assert_eq!(first.iter().map(|message| message.offset).collect::<Vec<_>>(), vec![0, 1, 2, 3, 4]);
+        assert_eq!(first.into_iter().map(|message| message.payload).collect::<Vec<_>>(), (0..5u8).map(|i| vec![i]).collect::<Vec<_>>());
+
+        // The stream waits for messages produced after it caught up
+        executor::block_on(producer.send(&[5])).unwrap();
+        let message = executor::block_on(messages.next()).unwrap();
+        assert_eq!((message.offset, message.payload), (5, vec![5]));
+    }
+}
@@ -0,0 +1,45 @@
+extern crate queue;
+
+use std::env;
+use std::net::TcpListener;
+use std::path::Path;
+use std::process;
+
+use queue::{Kafka, Server};
+
+// Serves the topics in a directory to Kafka clients:
+//
+//     server [dir] [address]
+//
+// The directory defaults to ./data and the address to 127.0.0.1:9092.
+fn main() {
+    let mut args = env::args().skip(1);
+    let dir = args.next().unwrap_or_else(|| "./data".to_string());
+    let address = args.next().unwrap_or_else(|| "127.0.0.1:9092".to_string());
+
+    let kafka = match Kafka::new(Path::new(&dir)).and_then(|kafka| kafka.open().map(|_| kafka)) {
+        Ok(kafka) => kafka,
+        Err(e) => {
+            eprintln!("Failed to open {}: {}", dir, e);
+            process::exit(1);
+        },
+    };
+
+    let listener = match TcpListener::bind(&address) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Failed to listen on {}: {}", address, e);
+            process::exit(1);
+        },
+    };
+
+    println!("Serving {} on {}", dir, address);
+    let server = Server::new(kafka).with_connection_error_handler(|peer, e| match peer {
+        Some(peer) => eprintln!("Closing connection from {}: {}", peer, e),
+        None => eprintln!("Closing connection: {}", e),
+    });
+    if let Err(e) = server.serve(listener) {
+        eprintln!("Server stopped: {}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,76 @@
+use std::time::Duration;
+
+use segment::DEFAULT_INDEX_INTERVAL_BYTES;
+
+pub const DEFAULT_BLOCK_SIZE: usize = 512;
+
+#[derive(Clone, Debug)]
+pub struct TopicConfig {
+    // Size of the blocks new segments are written in. Each segment records its own block size, so
+    // changing this leaves existing segments readable.
+    pub block_size: usize,
+    // Seal the active segment and start a new one once any of these limits is reached
+    pub segment_bytes: Option<u64>,
+    pub segment_messages: Option<u64>,
+    pub segment_age: Option<Duration>,
+    pub sync_policy: SyncPolicy,
+    // Bytes of messages between entries in each segment's offset index
+    pub index_interval_bytes: u64,
+    // Sealed segments are deleted once their newest message is older than this, or while the
+    // topic takes up more than this many bytes
+    pub retention_age: Option<Duration>,
+    pub retention_bytes: Option<u64>,
+    pub cleanup_policy: CleanupPolicy,
+    // How long compaction keeps a tombstone around, so consumers get to see the delete, counted
+    // from when the segment holding it was sealed
+    pub delete_retention: Duration,
+    // Partitions given to topics created by producing to them
+    pub num_partitions: u32
+}
+
+impl Default for TopicConfig {
+    fn default() -> TopicConfig {
+        TopicConfig {
+            block_size: DEFAULT_BLOCK_SIZE,
+            segment_bytes: Some(1024 * 1024 * 1024),
+            segment_messages: None,
+            segment_age: Some(Duration::from_secs(7 * 24 * 60 * 60)),
+            sync_policy: SyncPolicy::EveryMessage,
+            index_interval_bytes: DEFAULT_INDEX_INTERVAL_BYTES,
+            retention_age: Some(Duration::from_secs(7 * 24 * 60 * 60)),
+            retention_bytes: None,
+            cleanup_policy: CleanupPolicy::Delete,
+            delete_retention: Duration::from_secs(24 * 60 * 60),
+            num_partitions: 1
+        }
+    }
+}
+
+// How a topic gets rid of old messages
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CleanupPolicy {
+    // Delete whole segments past the retention limits
+    Delete,
+    // Keep the latest record for each key
+    Compact
+}
+
+// How often a topic syncs appended messages to disk. Segments are always synced when sealed or closed.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SyncPolicy {
+    EveryMessage,
+    EveryMessages(u64),
+    // Checked on each produce, and by a background thread so the last messages before a lull get synced too
+    Interval(Duration),
+    // Leave it to the OS
+    Never
+}
+
+// When a produce returns
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Acks {
+    // Once the message has been handed to the OS, leaving durability to the topic's sync policy
+    Written,
+    // Once the message has been synced to disk
+    Durable
+}
@@ -0,0 +1,110 @@
+use std::cmp;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use error::{Error, Result};
+use notify::AppendNotifier;
+use offsets::OffsetStore;
+use partition::Partition;
+use record;
+use segment::{Message, Offset};
+use topic::{self, Cursor};
+
+// Reads a single partition of a topic in order, independently of any other consumer
+pub struct Consumer {
+    partition: Arc<Partition>,
+    cursor: Cursor,
+    group: Option<Group>
+}
+
+// The consumer group a consumer commits its position for
+struct Group {
+    offsets: Arc<OffsetStore>,
+    name: String,
+    topic_name: String,
+    partition: u32
+}
+
+impl Consumer {
+    pub(crate) fn new(partition: Arc<Partition>) -> Result<Consumer> {
+        let cursor = {
+            let log = partition.read();
+            log.cursor_at(log.log_start_offset())?
+        };
+        Ok(Consumer { partition, cursor, group: None })
+    }
+
+    // A consumer for `group`, resuming from the group's committed offset. Starts from the first
+    // message the topic still holds if nothing was committed, or the commit has since been deleted.
+    pub(crate) fn in_group(partition: Arc<Partition>, topic_name: &str, partition_id: u32, offsets: Arc<OffsetStore>, group: &str) -> Result<Consumer> {
+        let cursor = {
+            let log = partition.read();
+            let offset = match offsets.committed(group, topic_name, partition_id) {
+                Some(offset) => cmp::min(cmp::max(offset, log.log_start_offset()), log.next_offset()),
+                None => log.log_start_offset(),
+            };
+            log.cursor_at(offset)?
+        };
+
+        let group = Group { offsets, name: group.to_string(), topic_name: topic_name.to_string(), partition: partition_id };
+        Ok(Consumer { partition, cursor, group: Some(group) })
+    }
+
+    // Returns the next message, or `None` once caught up with the end of the topic. The partition
+    // is only locked to find the segment to read, so polling doesn't hold up producers.
+    pub fn poll(&mut self) -> Result<Option<Message>> {
+        let partition = &self.partition;
+        topic::read(|| partition.read(), &mut self.cursor)
+    }
+
+    // Like `poll`, but waits up to `timeout` for a message to be produced if there is none yet
+    pub fn poll_timeout(&mut self, timeout: Duration) -> Result<Option<Message>> {
+        let deadline = Instant::now() + timeout;
+        let appends = self.partition.read().appends();
+
+        loop {
+            // Taken before polling, so an append made meanwhile ends the wait. Compaction can leave
+            // the position short of it with nothing to read, so the position won't do.
+            let next_offset = self.partition.read().next_offset();
+            if let Some(message) = self.poll()? {
+                return Ok(Some(message));
+            }
+
+            if !appends.wait_for(next_offset, deadline) {
+                return Ok(None);
+            }
+        }
+    }
+
+    pub fn seek(&mut self, offset: Offset) -> Result<()> {
+        self.cursor = self.partition.read().cursor_at(offset)?;
+        Ok(())
+    }
+
+    // Moves to the first message produced at or after `time`, returning its offset
+    pub fn seek_to_timestamp(&mut self, time: SystemTime) -> Result<Offset> {
+        let offset = self.partition.read().offset_for_timestamp(record::to_millis(time))?;
+        self.seek(offset)?;
+        Ok(offset)
+    }
+
+    // Signals appends to the partition, along with the offset the next one gets
+    pub(crate) fn appends(&self) -> (Arc<AppendNotifier>, Offset) {
+        let log = self.partition.read();
+        (log.appends(), log.next_offset())
+    }
+
+    // Offset of the next message to be returned
+    pub fn position(&self) -> Offset {
+        self.cursor.offset()
+    }
+
+    // Durably commits the current position for the consumer's group, so the group resumes from
+    // here after a restart
+    pub fn commit(&self) -> Result<()> {
+        match self.group {
+            Some(ref group) => group.offsets.commit(&group.name, &group.topic_name, group.partition, self.position()),
+            None => Err(Error::NoConsumerGroup),
+        }
+    }
+}
@@ -0,0 +1,465 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use assignor::{Assignment, AssignmentStrategy, MemberId, RangeStrategy, TopicPartition};
+use error::{Error, Result};
+
+pub const DEFAULT_SESSION_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Least time the expirer waits between looking for timed out members
+const MIN_EXPIRY_INTERVAL: Duration = Duration::from_millis(10);
+
+// Told when a rebalance moves partitions away from or to a member. Every member's partitions are
+// revoked before any are assigned, so no partition ever has two consumers. The callbacks of one
+// rebalance are all made before those of the next, one at a time, though not necessarily on the
+// thread whose call caused the rebalance.
+pub trait RebalanceListener: Send + Sync {
+    fn on_partitions_revoked(&self, partitions: &[TopicPartition]);
+    fn on_partitions_assigned(&self, partitions: &[TopicPartition]);
+}
+
+// Tracks the members of each consumer group and shares the partitions of the topics they
+// subscribe to between them. The partitions are reassigned whenever a member joins or leaves,
+// including by missing heartbeats for longer than the session timeout, and whenever a subscribed
+// topic changes its number of partitions.
+pub struct GroupCoordinator {
+    session_timeout: Duration,
+    strategy: Box<dyn AssignmentStrategy>,
+    state: Mutex<CoordinatorState>
+}
+
+struct CoordinatorState {
+    // Number of partitions of each topic
+    partitions: BTreeMap<String, u32>,
+    groups: HashMap<String, Group>,
+    // Notifications of each rebalance, in order, waiting to be delivered
+    pending: VecDeque<Vec<Notification>>,
+    // Whether a thread is delivering the pending notifications
+    delivering: bool
+}
+
+struct Group {
+    // Bumped by every rebalance
+    generation: u64,
+    members: BTreeMap<MemberId, Member>,
+    assignment: Assignment
+}
+
+struct Member {
+    topics: Vec<String>,
+    last_heartbeat: Instant,
+    listener: Option<Arc<dyn RebalanceListener>>
+}
+
+// Partitions a member is losing and gaining in a rebalance
+struct Notification {
+    listener: Arc<dyn RebalanceListener>,
+    revoked: Vec<TopicPartition>,
+    assigned: Vec<TopicPartition>
+}
+
+impl GroupCoordinator {
+    pub fn new() -> GroupCoordinator {
+        GroupCoordinator {
+            session_timeout: DEFAULT_SESSION_TIMEOUT,
+            strategy: Box::new(RangeStrategy),
+            state: Mutex::new(CoordinatorState {
+                partitions: BTreeMap::new(),
+                groups: HashMap::new(),
+                pending: VecDeque::new(),
+                delivering: false
+            })
+        }
+    }
+
+    // Members that don't heartbeat for this long are removed from their group
+    pub fn with_session_timeout(mut self, session_timeout: Duration) -> GroupCoordinator {
+        self.session_timeout = session_timeout;
+        self
+    }
+
+    pub fn with_strategy<S: AssignmentStrategy + 'static>(mut self, strategy: S) -> GroupCoordinator {
+        self.strategy = Box::new(strategy);
+        self
+    }
+
+    // Records how many partitions the topic has, rebalancing the groups subscribed to it if that changed
+    pub fn set_partitions(&self, topic: &str, num_partitions: u32) {
+        {
+            let mut state = self.lock();
+            if state.partitions.get(topic) == Some(&num_partitions) {
+                return;
+            }
+            state.partitions.insert(topic.to_string(), num_partitions);
+
+            let CoordinatorState { ref partitions, ref mut groups, ref mut pending, .. } = *state;
+            for group in groups.values_mut().filter(|group| group.subscribes_to(topic)) {
+                pending.push_back(group.rebalance(&*self.strategy, partitions, Vec::new()));
+            }
+        }
+
+        self.deliver();
+    }
+
+    // Adds the member to the group, or updates its subscription if it's already a member, and
+    // rebalances the group. Returns the partitions the member now consumes.
+    pub fn join(&self, group_id: &str, member_id: &str, topics: &[&str], listener: Option<Arc<dyn RebalanceListener>>) -> Vec<TopicPartition> {
+        let assigned = {
+            let mut state = self.lock();
+            let CoordinatorState { ref partitions, ref mut groups, ref mut pending, .. } = *state;
+
+            let group = groups.entry(group_id.to_string()).or_insert_with(Group::new);
+            let member = Member { topics: topics.iter().map(|topic| topic.to_string()).collect(), last_heartbeat: Instant::now(), listener };
+            group.members.insert(member_id.to_string(), member);
+
+            pending.push_back(group.rebalance(&*self.strategy, partitions, Vec::new()));
+            group.assigned_to(member_id)
+        };
+
+        self.deliver();
+        assigned
+    }
+
+    // Removes the member from the group, handing its partitions to the other members
+    pub fn leave(&self, group_id: &str, member_id: &str) -> Result<()> {
+        {
+            let mut state = self.lock();
+            let CoordinatorState { ref partitions, ref mut groups, ref mut pending, .. } = *state;
+
+            let group = groups.get_mut(group_id).ok_or_else(|| unknown_member(group_id, member_id))?;
+            let member = group.members.remove(member_id).ok_or_else(|| unknown_member(group_id, member_id))?;
+
+            pending.push_back(group.rebalance(&*self.strategy, partitions, vec![(member_id.to_string(), member)]));
+            if group.members.is_empty() {
+                groups.remove(group_id);
+            }
+        }
+
+        self.deliver();
+        Ok(())
+    }
+
+    // Keeps the member in the group, returning the group's generation. Fails once the member has
+    // been removed for missing its session timeout, after which it has to join again.
+    pub fn heartbeat(&self, group_id: &str, member_id: &str) -> Result<u64> {
+        self.expire_members_at(Instant::now());
+
+        let mut state = self.lock();
+        let group = state.groups.get_mut(group_id).ok_or_else(|| unknown_member(group_id, member_id))?;
+        let member = group.members.get_mut(member_id).ok_or_else(|| unknown_member(group_id, member_id))?;
+
+        member.last_heartbeat = Instant::now();
+        Ok(group.generation)
+    }
+
+    // Removes every member whose session has timed out, returning how many were removed
+    pub fn expire_members(&self) -> usize {
+        self.expire_members_at(Instant::now())
+    }
+
+    // The partitions the member consumes
+    pub fn assignment(&self, group_id: &str, member_id: &str) -> Result<Vec<TopicPartition>> {
+        let state = self.lock();
+        match state.groups.get(group_id) {
+            Some(group) if group.members.contains_key(member_id) => Ok(group.assigned_to(member_id)),
+            _ => Err(unknown_member(group_id, member_id)),
+        }
+    }
+
+    pub fn members(&self, group_id: &str) -> Vec<MemberId> {
+        self.lock().groups.get(group_id).map(|group| group.members.keys().cloned().collect()).unwrap_or_default()
+    }
+
+    // Counts the rebalances of the group, or None if it has no members
+    pub fn generation(&self, group_id: &str) -> Option<u64> {
+        self.lock().groups.get(group_id).map(|group| group.generation)
+    }
+
+    fn expire_members_at(&self, now: Instant) -> usize {
+        let mut num_expired = 0;
+        {
+            let mut state = self.lock();
+            let CoordinatorState { ref partitions, ref mut groups, ref mut pending, .. } = *state;
+
+            for group in groups.values_mut() {
+                let expired: Vec<MemberId> = group.members.iter()
+                    .filter(|&(_, member)| now.duration_since(member.last_heartbeat) > self.session_timeout)
+                    .map(|(member_id, _)| member_id.clone())
+                    .collect();
+                if expired.is_empty() {
+                    continue;
+                }
+
+                num_expired += expired.len();
+                let departed = expired.into_iter().filter_map(|member_id| group.members.remove(&member_id).map(|member| (member_id, member))).collect();
+                pending.push_back(group.rebalance(&*self.strategy, partitions, departed));
+            }
+            groups.retain(|_, group| !group.members.is_empty());
+        }
+
+        self.deliver();
+        num_expired
+    }
+
+    // Calls the listeners about each pending rebalance in turn, once the coordinator is unlocked
+    // so they may call back into it. Rebalances while another thread is delivering, including
+    // those caused by the listeners themselves, are left for that thread to deliver after the
+    // ones before them.
+    fn deliver(&self) {
+        {
+            let mut state = self.lock();
+            if state.delivering {
+                return;
+            }
+            state.delivering = true;
+        }
+
+        loop {
+            let notifications = {
+                let mut state = self.lock();
+                match state.pending.pop_front() {
+                    Some(notifications) => notifications,
+                    None => {
+                        state.delivering = false;
+                        return;
+                    },
+                }
+            };
+
+            notify(notifications);
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, CoordinatorState> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Default for GroupCoordinator {
+    fn default() -> GroupCoordinator {
+        GroupCoordinator::new()
+    }
+}
+
+impl Group {
+    fn new() -> Group {
+        Group { generation: 0, members: BTreeMap::new(), assignment: Assignment::new() }
+    }
+
+    fn subscribes_to(&self, topic: &str) -> bool {
+        self.members.values().any(|member| member.topics.iter().any(|t| t == topic))
+    }
+
+    fn assigned_to(&self, member_id: &str) -> Vec<TopicPartition> {
+        self.assignment.get(member_id).cloned().unwrap_or_default()
+    }
+
+    // Reassigns the partitions between the current members, returning who to tell about the
+    // partitions that moved. `departed` are the members that just left, which lose everything.
+    fn rebalance(&mut self, strategy: &dyn AssignmentStrategy, partitions: &BTreeMap<String, u32>, departed: Vec<(MemberId, Member)>) -> Vec<Notification> {
+        let subscriptions = self.members.iter().map(|(member_id, member)| (member_id.clone(), member.topics.clone())).collect();
+        let assignment = strategy.assign(&subscriptions, partitions, &self.assignment);
+
+        let listeners = self.members.iter().map(|(member_id, member)| (member_id, &member.listener))
+            .chain(departed.iter().map(|(member_id, member)| (member_id, &member.listener)));
+
+        let mut notifications = Vec::new();
+        for (member_id, listener) in listeners {
+            let before: BTreeSet<&TopicPartition> = self.assignment.get(member_id).into_iter().flatten().collect();
+            let after: BTreeSet<&TopicPartition> = assignment.get(member_id).into_iter().flatten().collect();
+
+            let revoked: Vec<TopicPartition> = before.difference(&after).map(|&partition| partition.clone()).collect();
+            let assigned: Vec<TopicPartition> = after.difference(&before).map(|&partition| partition.clone()).collect();
+            if let Some(ref listener) = *listener {
+                if !revoked.is_empty() || !assigned.is_empty() {
+                    notifications.push(Notification { listener: listener.clone(), revoked, assigned });
+                }
+            }
+        }
+
+        self.assignment = assignment;
+        self.generation += 1;
+        notifications
+    }
+}
+
+// Tells the listeners about a rebalance, revoking partitions before assigning any
+fn notify(notifications: Vec<Notification>) {
+    for notification in notifications.iter().filter(|notification| !notification.revoked.is_empty()) {
+        notification.listener.on_partitions_revoked(&notification.revoked);
+    }
+
+    for notification in notifications.iter().filter(|notification| !notification.assigned.is_empty()) {
+        notification.listener.on_partitions_assigned(&notification.assigned);
+    }
+}
+
+// Removes the members of the coordinator's groups as their sessions time out, without waiting for
+// another member's heartbeat. Stops once the coordinator is dropped.
+pub fn spawn_expirer(coordinator: &Arc<GroupCoordinator>) {
+    let interval = (coordinator.session_timeout / 4).max(MIN_EXPIRY_INTERVAL);
+    let coordinator = Arc::downgrade(coordinator);
+
+    thread::spawn(move || {
+        loop {
+            thread::sleep(interval);
+
+            match coordinator.upgrade() {
+                Some(coordinator) => coordinator.expire_members(),
+                None => return,
+            };
+        }
+    });
+}
+
+fn unknown_member(group_id: &str, member_id: &str) -> Error {
+    Error::UnknownMember { group: group_id.to_string(), member: member_id.to_string() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    // Keeps a log of the callbacks it receives
+    struct RecordingListener {
+        name: &'static str,
+        events: Arc<Mutex<Vec<String>>>
+    }
+
+    impl RebalanceListener for RecordingListener {
+        fn on_partitions_revoked(&self, partitions: &[TopicPartition]) {
+            self.events.lock().unwrap().push(format!("{} revoked {:?}", self.name, numbers(partitions)));
+        }
+
+        fn on_partitions_assigned(&self, partitions: &[TopicPartition]) {
+            self.events.lock().unwrap().push(format!("{} assigned {:?}", self.name, numbers(partitions)));
+        }
+    }
+
+    fn numbers(partitions: &[TopicPartition]) -> Vec<u32> {
+        partitions.iter().map(|tp| tp.partition).collect()
+    }
+
+    fn listener(name: &'static str, events: &Arc<Mutex<Vec<String>>>) -> Option<Arc<dyn RebalanceListener>> {
+        Some(Arc::new(RecordingListener { name, events: events.clone() }))
+    }
+
+    fn take(events: &Arc<Mutex<Vec<String>>>) -> Vec<String> {
+        events.lock().unwrap().drain(..).collect()
+    }
+
+    #[test]
+    fn test_join_and_leave() {
+        let coordinator = GroupCoordinator::new();
+        coordinator.set_partitions("foo", 4);
+        let events = Arc::new(Mutex::new(Vec::new()));
+
+        assert_eq!(numbers(&coordinator.join("g", "a", &["foo"], listener("a", &events))), vec![0, 1, 2, 3]);
+        assert_eq!(take(&events), vec!["a assigned [0, 1, 2, 3]"]);
+
+        assert_eq!(numbers(&coordinator.join("g", "b", &["foo"], listener("b", &events))), vec![2, 3]);
+        assert_eq!(take(&events), vec!["a revoked [2, 3]", "b assigned [2, 3]"]);
+        assert_eq!(coordinator.generation("g"), Some(2));
+        assert_eq!(coordinator.members("g"), vec!["a", "b"]);
+
+        coordinator.set_partitions("foo", 6);
+        assert_eq!(take(&events), vec!["b revoked [2]", "a assigned [2]", "b assigned [4, 5]"]);
+
+        coordinator.leave("g", "a").unwrap();
+        assert_eq!(take(&events), vec!["a revoked [0, 1, 2]", "b assigned [0, 1, 2]"]);
+        assert_eq!(numbers(&coordinator.assignment("g", "b").unwrap()), vec![0, 1, 2, 3, 4, 5]);
+        assert!(coordinator.leave("g", "a").is_err());
+        assert!(coordinator.assignment("g", "a").is_err());
+
+        coordinator.leave("g", "b").unwrap();
+        assert_eq!(coordinator.generation("g"), None);
+    }
+
+    #[test]
+    fn test_session_timeout() {
+        let coordinator = GroupCoordinator::new().with_session_timeout(Duration::from_secs(1));
+        coordinator.set_partitions("foo", 2);
+        coordinator.join("g", "a", &["foo"], None);
+        coordinator.join("g", "b", &["foo"], None);
+        assert_eq!(coordinator.heartbeat("g", "a").unwrap(), 2);
+
+        assert_eq!(coordinator.expire_members_at(Instant::now()), 0);
+        coordinator.lock().groups.get_mut("g").unwrap().members.get_mut("b").unwrap().last_heartbeat -= Duration::from_secs(2);
+
+        assert_eq!(coordinator.heartbeat("g", "a").unwrap(), 3);
+        assert_eq!(numbers(&coordinator.assignment("g", "a").unwrap()), vec![0, 1]);
+        match coordinator.heartbeat("g", "b") {
+            Err(Error::UnknownMember { .. }) => (),
+            _ => panic!("Expected unknown member"),
+        }
+    }
+
+    #[test]
+    fn test_spawn_expirer() {
+        let coordinator = Arc::new(GroupCoordinator::new().with_session_timeout(Duration::from_millis(50)));
+        coordinator.set_partitions("foo", 2);
+        let events = Arc::new(Mutex::new(Vec::new()));
+        coordinator.join("g", "a", &["foo"], listener("a", &events));
+        spawn_expirer(&coordinator);
+
+        // The member goes without anyone else calling into the coordinator
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while coordinator.generation("g").is_some() {
+            assert!(Instant::now() < deadline, "Member never expired");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(take(&events), vec!["a assigned [0, 1]", "a revoked [0, 1]"]);
+    }
+
+    // Holds up the first revoke it's told about until released
+    struct BlockingListener {
+        events: Arc<Mutex<Vec<String>>>,
+        revoking: Mutex<Option<mpsc::Sender<()>>>,
+        release: Mutex<mpsc::Receiver<()>>
+    }
+
+    impl RebalanceListener for BlockingListener {
+        fn on_partitions_revoked(&self, partitions: &[TopicPartition]) {
+            if let Some(revoking) = self.revoking.lock().unwrap().take() {
+                revoking.send(()).unwrap();
+                self.release.lock().unwrap().recv().unwrap();
+            }
+            self.events.lock().unwrap().push(format!("a revoked {:?}", numbers(partitions)));
+        }
+
+        fn on_partitions_assigned(&self, partitions: &[TopicPartition]) {
+            self.events.lock().unwrap().push(format!("a assigned {:?}", numbers(partitions)));
+        }
+    }
+
+    #[test]
+    fn test_rebalances_delivered_in_order() {
+        let coordinator = Arc::new(GroupCoordinator::new());
+        coordinator.set_partitions("foo", 4);
+        let events = Arc::new(Mutex::new(Vec::new()));
+
+        let (revoking, revoke_started) = mpsc::channel();
+        let (release, released) = mpsc::channel();
+        let blocking = BlockingListener { events: events.clone(), revoking: Mutex::new(Some(revoking)), release: Mutex::new(released) };
+        coordinator.join("g", "a", &["foo"], Some(Arc::new(blocking)));
+        take(&events);
+
+        let joining = {
+            let coordinator = coordinator.clone();
+            let events = events.clone();
+            thread::spawn(move || coordinator.join("g", "b", &["foo"], listener("b", &events)))
+        };
+
+        // A second rebalance while the first is still being delivered waits its turn
+        revoke_started.recv().unwrap();
+        assert_eq!(numbers(&coordinator.join("g", "c", &["foo"], listener("c", &events))), vec![3]);
+        assert!(take(&events).is_empty());
+
+        release.send(()).unwrap();
+        joining.join().unwrap();
+        assert_eq!(take(&events), vec!["a revoked [2, 3]", "b assigned [2, 3]", "b revoked [3]", "c assigned [3]"]);
+    }
+}
@@ -0,0 +1,85 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::result;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    // A chunk in a segment file failed validation
+    Corruption { segment: PathBuf, position: u64 },
+    UnknownChunkType(u8),
+    EmptyMessage,
+    TopicNotFound(String),
+    TopicExists(String),
+    // The name belongs to a topic kept internally, such as the committed offsets
+    ReservedTopicName(String),
+    PartitionNotFound { topic: String, partition: u32 },
+    OffsetOutOfRange(u64),
+    InvalidSegmentName(PathBuf),
+    // A segment file is missing its header, or the header fails validation
+    InvalidSegmentHeader(PathBuf),
+    UnsupportedSegmentVersion { segment: PathBuf, version: u32 },
+    // Too small to hold a chunk
+    InvalidBlockSize(usize),
+    // A topic needs at least one partition
+    InvalidPartitionCount(u32),
+    OutOfBounds { index: usize, len: usize },
+    // Committing needs a consumer that belongs to a group
+    NoConsumerGroup,
+    // Not a member of the group, possibly having been removed for missing heartbeats
+    UnknownMember { group: String, member: String },
+    // The thread doing the I/O for an async producer went away before answering
+    Disconnected,
+    // A protocol request that can't be parsed
+    MalformedRequest,
+    UnsupportedRequest { api_key: i16, api_version: i16 },
+    // Record batches have to arrive uncompressed
+    UnsupportedCompression
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref e) => write!(f, "I/O error: {}", e),
+            Error::Corruption { ref segment, position } => write!(f, "Corrupt chunk in {:?} at {}", segment, position),
+            Error::UnknownChunkType(x) => write!(f, "Unknown chunk type: {}", x),
+            Error::EmptyMessage => write!(f, "Can't handle empty messages"),
+            Error::TopicNotFound(ref topic) => write!(f, "Topic not found: {}", topic),
+            Error::TopicExists(ref topic) => write!(f, "Topic already exists: {}", topic),
+            Error::ReservedTopicName(ref topic) => write!(f, "Topic name is reserved: {}", topic),
+            Error::PartitionNotFound { ref topic, partition } => write!(f, "Partition {} of topic {} not found", partition, topic),
+            Error::OffsetOutOfRange(offset) => write!(f, "Offset out of range: {}", offset),
+            Error::InvalidSegmentName(ref path) => write!(f, "Invalid segment file name: {:?}", path),
+            Error::InvalidSegmentHeader(ref path) => write!(f, "Invalid segment file header: {:?}", path),
+            Error::UnsupportedSegmentVersion { ref segment, version } => write!(f, "Unsupported version {} of segment {:?}", version, segment),
+            Error::InvalidBlockSize(size) => write!(f, "Invalid block size: {}", size),
+            Error::InvalidPartitionCount(count) => write!(f, "Invalid partition count: {}", count),
+            Error::OutOfBounds { index, len } => write!(f, "Index {} out of bounds for buffer of {} bytes", index, len),
+            Error::NoConsumerGroup => write!(f, "Consumer is not part of a consumer group"),
+            Error::UnknownMember { ref group, ref member } => write!(f, "Unknown member {} of group {}", member, group),
+            Error::Disconnected => write!(f, "Async producer's I/O thread stopped"),
+            Error::MalformedRequest => write!(f, "Malformed request"),
+            Error::UnsupportedRequest { api_key, api_version } => write!(f, "Unsupported request: api key {}, version {}", api_key, api_version),
+            Error::UnsupportedCompression => write!(f, "Compressed record batches are not supported"),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
@@ -0,0 +1,117 @@
+use std::sync::{Condvar, Mutex};
+
+use error::Result;
+use segment::Offset;
+
+// Tracks how much of a topic has been synced to disk, letting producers waiting on durability
+// share a single sync: whoever arrives while no sync is running performs one covering everything
+// written so far, and everyone else waits for it to finish.
+pub struct GroupCommit {
+    state: Mutex<CommitState>,
+    synced: Condvar
+}
+
+struct CommitState {
+    // Every offset below this has been synced
+    durable_offset: Offset,
+    syncing: bool,
+    num_syncs: u64
+}
+
+impl GroupCommit {
+    pub fn new(durable_offset: Offset) -> GroupCommit {
+        GroupCommit {
+            state: Mutex::new(CommitState { durable_offset, syncing: false, num_syncs: 0 }),
+            synced: Condvar::new()
+        }
+    }
+
+    pub fn durable_offset(&self) -> Offset {
+        self.lock().durable_offset
+    }
+
+    // How many syncs have been performed
+    pub fn num_syncs(&self) -> u64 {
+        self.lock().num_syncs
+    }
+
+    // Blocks until every offset below `offset` is durable. `sync` syncs everything written so far,
+    // returning the offset that follows it.
+    pub fn commit<F>(&self, offset: Offset, sync: F) -> Result<()> where F: FnOnce() -> Result<Offset> {
+        let mut state = self.lock();
+
+        loop {
+            if state.durable_offset >= offset {
+                return Ok(());
+            }
+
+            if !state.syncing {
+                break;
+            }
+
+            state = self.synced.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+
+        state.syncing = true;
+        state.num_syncs += 1;
+        drop(state);
+
+        let result = sync();
+
+        let mut state = self.lock();
+        state.syncing = false;
+        if let Ok(synced_offset) = result {
+            state.durable_offset = state.durable_offset.max(synced_offset);
+        }
+        self.synced.notify_all();
+
+        result.map(|_| ())
+    }
+
+    fn lock(&self) -> ::std::sync::MutexGuard<'_, CommitState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_commit_skips_durable_offsets() {
+        let commit = GroupCommit::new(5);
+        commit.commit(5, || panic!("Should not sync")).unwrap();
+
+        commit.commit(6, || Ok(8)).unwrap();
+        assert_eq!(commit.durable_offset(), 8);
+    }
+
+    #[test]
+    fn test_concurrent_commits_share_sync() {
+        let commit = Arc::new(GroupCommit::new(0));
+        let num_syncs = Arc::new(AtomicUsize::new(0));
+
+        let handles: Vec<_> = (1..9).map(|offset| {
+            let commit = commit.clone();
+            let num_syncs = num_syncs.clone();
+            thread::spawn(move || {
+                commit.commit(offset, || {
+                    num_syncs.fetch_add(1, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(50));
+                    Ok(8)
+                }).unwrap();
+            })
+        }).collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(commit.durable_offset(), 8);
+        assert_eq!(num_syncs.load(Ordering::SeqCst), 1);
+    }
+}
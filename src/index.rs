@@ -0,0 +1,319 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+use error::{Error, Result};
+use segment::{read_u64, write_u64, Offset};
+
+// Layout of an index entry: a key, then the value it maps to
+pub const INDEX_KEY_OFFSET: usize = 0;   // 0-7
+pub const INDEX_VALUE_OFFSET: usize = 8; // 8-15
+
+pub const INDEX_ENTRY_BYTES: usize = 16; // key(8) + value(8)
+
+// The index files belonging to the segment file at `segment_path`
+pub fn index_path(segment_path: &Path) -> PathBuf {
+    segment_path.with_extension("index")
+}
+
+pub fn time_index_path(segment_path: &Path) -> PathBuf {
+    segment_path.with_extension("timeindex")
+}
+
+// Entries of an index, both keys and values increasing, kept in memory and mirrored to a file
+struct IndexFile {
+    path: PathBuf,
+    file: Option<File>,
+    entries: Vec<(u64, u64)>,
+    unwritten: usize
+}
+
+impl IndexFile {
+    fn new(path: PathBuf) -> IndexFile {
+        IndexFile { path, file: None, entries: Vec::new(), unwritten: 0 }
+    }
+
+    // Returns None if the file is missing or damaged
+    fn load(path: PathBuf) -> Result<Option<IndexFile>> {
+        let mut index = IndexFile::new(path);
+
+        let mut bytes = Vec::new();
+        match File::open(&index.path) {
+            Ok(mut file) => file.read_to_end(&mut bytes)?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(Error::Io(e)),
+        };
+
+        // A trailing partial entry is left over from a torn write
+        for entry in bytes.chunks(INDEX_ENTRY_BYTES).filter(|entry| entry.len() == INDEX_ENTRY_BYTES) {
+            let key = read_u64(entry, INDEX_KEY_OFFSET)?;
+            let value = read_u64(entry, INDEX_VALUE_OFFSET)?;
+
+            let is_increasing = index.entries.last().map(|&(k, v)| key > k && value > v).unwrap_or(true);
+            if !is_increasing {
+                return Ok(None);
+            }
+            index.entries.push((key, value));
+        }
+
+        Ok(Some(index))
+    }
+
+    fn create(path: PathBuf) -> Result<IndexFile> {
+        let mut index = IndexFile::new(path);
+        index.file = Some(File::create(&index.path)?);
+        Ok(index)
+    }
+
+    fn push(&mut self, key: u64, value: u64) {
+        self.entries.push((key, value));
+        self.unwritten += 1;
+    }
+
+    // The last entry with a key at or before `key`
+    fn floor(&self, key: u64) -> Option<(u64, u64)> {
+        match self.entries.partition_point(|&(k, _)| k <= key) {
+            0 => None,
+            i => Some(self.entries[i - 1]),
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if self.unwritten == 0 {
+            return Ok(());
+        }
+
+        if self.file.is_none() {
+            self.file = Some(OpenOptions::new().append(true).create(true).open(&self.path)?);
+        }
+
+        let mut bytes = vec![0; self.unwritten * INDEX_ENTRY_BYTES];
+        let new_entries = &self.entries[(self.entries.len() - self.unwritten)..];
+        for (entry, &(key, value)) in bytes.chunks_mut(INDEX_ENTRY_BYTES).zip(new_entries) {
+            write_u64(entry, key, INDEX_KEY_OFFSET)?;
+            write_u64(entry, value, INDEX_VALUE_OFFSET)?;
+        }
+
+        if let Some(file) = self.file.as_mut() {
+            file.write_all(&bytes)?;
+        }
+        self.unwritten = 0;
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.flush()?;
+
+        if let Some(file) = self.file.as_mut() {
+            file.sync_data()?;
+        }
+
+        Ok(())
+    }
+
+    fn close(&mut self) {
+        self.file = None;
+    }
+}
+
+// Sparse map from offsets to the positions of their messages within a segment, kept in an `.index`
+// file beside it. There is one entry for every `interval_bytes` of messages.
+pub struct OffsetIndex {
+    file: IndexFile,
+    interval_bytes: u64
+}
+
+impl OffsetIndex {
+    pub fn new(segment_path: &Path, interval_bytes: u64) -> OffsetIndex {
+        OffsetIndex { file: IndexFile::new(index_path(segment_path)), interval_bytes }
+    }
+
+    // Loads the index of the segment, returning None if the index file is missing or damaged
+    pub fn load(segment_path: &Path, interval_bytes: u64) -> Result<Option<OffsetIndex>> {
+        let file = IndexFile::load(index_path(segment_path))?;
+        Ok(file.map(|file| OffsetIndex { file, interval_bytes }))
+    }
+
+    // Starts the index file over, to be filled by `add`
+    pub fn create(segment_path: &Path, interval_bytes: u64) -> Result<OffsetIndex> {
+        Ok(OffsetIndex { file: IndexFile::create(index_path(segment_path))?, interval_bytes })
+    }
+
+    // Notes where the message at `offset` starts, indexing it if enough bytes have gone by since
+    // the last entry. Returns whether it was indexed. Entries are written out on `flush`.
+    pub fn add(&mut self, offset: Offset, position: u64) -> bool {
+        let is_due = match self.file.entries.last() {
+            Some(&(_, last_position)) => position >= last_position + self.interval_bytes,
+            None => true,
+        };
+
+        if is_due {
+            self.file.push(offset, position);
+        }
+        is_due
+    }
+
+    // The last indexed message at or before `offset`
+    pub fn lookup(&self, offset: Offset) -> Option<(Offset, u64)> {
+        self.file.floor(offset)
+    }
+
+    // The first and last entries
+    pub fn first(&self) -> Option<(Offset, u64)> {
+        self.file.entries.first().cloned()
+    }
+
+    pub fn last(&self) -> Option<(Offset, u64)> {
+        self.file.entries.last().cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.file.entries.len()
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.file.flush()
+    }
+
+    pub fn sync(&mut self) -> Result<()> {
+        self.file.sync()
+    }
+
+    pub fn close(&mut self) {
+        self.file.close();
+    }
+}
+
+// Sparse map from timestamps to offsets within a segment, kept in a `.timeindex` file beside it.
+// Each entry holds the largest timestamp seen so far and the offset of the message carrying it,
+// so entries only ever grow even when producers supply timestamps out of order.
+pub struct TimeIndex {
+    file: IndexFile
+}
+
+impl TimeIndex {
+    pub fn new(segment_path: &Path) -> TimeIndex {
+        TimeIndex { file: IndexFile::new(time_index_path(segment_path)) }
+    }
+
+    pub fn load(segment_path: &Path) -> Result<Option<TimeIndex>> {
+        Ok(IndexFile::load(time_index_path(segment_path))?.map(|file| TimeIndex { file }))
+    }
+
+    pub fn create(segment_path: &Path) -> Result<TimeIndex> {
+        Ok(TimeIndex { file: IndexFile::create(time_index_path(segment_path))? })
+    }
+
+    // Indexes the largest timestamp so far, unless an entry already covers it
+    pub fn add(&mut self, max_timestamp: u64, offset: Offset) {
+        let is_new = self.file.entries.last().map(|&(timestamp, _)| max_timestamp > timestamp).unwrap_or(true);
+        if is_new {
+            self.file.push(max_timestamp, offset);
+        }
+    }
+
+    // An offset at or after which reading finds the first message with a timestamp of at least
+    // `timestamp`, or None to read from the start of the segment. Every message up to and
+    // including the returned offset is older than `timestamp`.
+    pub fn lookup(&self, timestamp: u64) -> Option<Offset> {
+        match timestamp {
+            0 => None,
+            _ => self.file.floor(timestamp - 1).map(|(_, offset)| offset),
+        }
+    }
+
+    // The largest timestamp indexed
+    pub fn max_timestamp(&self) -> Option<(u64, Offset)> {
+        self.file.entries.last().cloned()
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.file.flush()
+    }
+
+    pub fn sync(&mut self) -> Result<()> {
+        self.file.sync()
+    }
+
+    pub fn close(&mut self) {
+        self.file.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_index_lookup() {
+        let path = Path::new("./test_data/index/test_index_lookup");
+        fs::create_dir_all(path.parent().unwrap());
+
+        let mut index = OffsetIndex::create(path, 100).unwrap();
+        index.add(10, 0);
+        index.add(11, 60);
+        index.add(12, 120);
+        index.add(13, 200);
+        index.add(14, 220);
+        index.flush().unwrap();
+        index.close();
+
+        assert_eq!(index.len(), 3);
+        assert_eq!(index.lookup(9), None);
+        assert_eq!(index.lookup(11), Some((10, 0)));
+        assert_eq!(index.lookup(12), Some((12, 120)));
+        assert_eq!(index.lookup(13), Some((12, 120)));
+        assert_eq!(index.lookup(100), Some((14, 220)));
+
+        let loaded = OffsetIndex::load(path, 100).unwrap().unwrap();
+        assert_eq!(loaded.file.entries, index.file.entries);
+    }
+
+    #[test]
+    fn test_load_index_with_torn_entry() {
+        let path = Path::new("./test_data/index/test_load_index_with_torn_entry");
+        fs::create_dir_all(path.parent().unwrap());
+        fs::remove_file(index_path(path));
+        assert!(OffsetIndex::load(path, 1).unwrap().is_none());
+
+        let mut index = OffsetIndex::create(path, 1).unwrap();
+        index.add(0, 0);
+        index.add(1, 40);
+        index.flush().unwrap();
+
+        let mut bytes = fs::read(index_path(path)).unwrap();
+        bytes.extend_from_slice(&[1, 2, 3]);
+        fs::write(index_path(path), &bytes).unwrap();
+        assert_eq!(OffsetIndex::load(path, 1).unwrap().unwrap().file.entries, vec![(0, 0), (1, 40)]);
+
+        // Out of order entries mean the file can't be trusted
+        bytes.truncate(INDEX_ENTRY_BYTES);
+        bytes.extend_from_slice(&bytes.clone());
+        fs::write(index_path(path), &bytes).unwrap();
+        assert!(OffsetIndex::load(path, 1).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_time_index_lookup() {
+        let path = Path::new("./test_data/index/test_time_index_lookup");
+        fs::create_dir_all(path.parent().unwrap());
+
+        let mut index = TimeIndex::create(path).unwrap();
+        index.add(1000, 0);
+        index.add(1000, 3);
+        index.add(2000, 5);
+        index.add(3000, 9);
+        index.flush().unwrap();
+
+        assert_eq!(index.lookup(0), None);
+        assert_eq!(index.lookup(1000), None);
+        assert_eq!(index.lookup(1001), Some(0));
+        assert_eq!(index.lookup(2500), Some(5));
+        assert_eq!(index.max_timestamp(), Some((3000, 9)));
+
+        let loaded = TimeIndex::load(path).unwrap().unwrap();
+        assert_eq!(loaded.file.entries, vec![(1000, 0), (2000, 5), (3000, 9)]);
+    }
+}
@@ -1,77 +1,456 @@
 use std::collections::HashMap;
+use std::collections::hash_map::Entry;
 use std::path::Path;
 use std::path::PathBuf;
 use std::fs::{self, DirEntry};
 use std::io;
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard, Weak};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
+use config::{CleanupPolicy, SyncPolicy, TopicConfig};
+use consumer::Consumer;
+use coordinator::{self, GroupCoordinator};
+use error::{Error, Result};
+use offsets::{OffsetStore, CONSUMER_OFFSETS_TOPIC};
+use partition::Partition;
+use partitioner::{HashPartitioner, Partitioner};
+use producer::Producer;
+use record::{self, Record, RecordMetadata};
+use segment::{Message, Offset, RecoveryReport};
 use topic::Topic;
 
-const BUFFER_SIZE: usize = 512;
+// Least time the flusher of a topic synced on an interval waits between passes
+const MIN_FLUSH_INTERVAL: Duration = Duration::from_millis(10);
 
-struct Kafka {
+// Told about a failed background sync, with the topic and partition it was of
+type SyncErrorHandler = dyn Fn(&str, u32, &Error) + Send + Sync;
+
+// A handle to the topics in a directory. Clones share the same topics, so a handle can be handed
+// to each thread. Every partition has its own lock, so different partitions are written in
+// parallel, and readers of a partition only wait on a write to that same partition.
+#[derive(Clone)]
+pub struct Kafka {
     dir: PathBuf,
-    topics: HashMap<String, Topic>
+    config: TopicConfig,
+    // The partitions of each topic, each one its own log
+    topics: Arc<RwLock<HashMap<String, Vec<Arc<Partition>>>>>,
+    partitioner: Arc<dyn Partitioner>,
+    offsets: Arc<OffsetStore>,
+    coordinator: Arc<GroupCoordinator>,
+    on_sync_error: Arc<RwLock<Option<Arc<SyncErrorHandler>>>>
 }
 
 impl Kafka {
-    fn new(dir: &Path) -> io::Result<Kafka> {
-        try!(fs::create_dir_all(&dir));
+    pub fn new(dir: &Path) -> Result<Kafka> {
+        Kafka::with_config(dir, TopicConfig::default())
+    }
+
+    // `config` applies to every topic but the internal one holding committed offsets
+    pub fn with_config(dir: &Path, config: TopicConfig) -> Result<Kafka> {
+        if config.num_partitions == 0 {
+            return Err(Error::InvalidPartitionCount(config.num_partitions));
+        }
+        fs::create_dir_all(dir)?;
+
+        let offsets = OffsetStore::open(dir)?;
+        let kafka = Kafka {
+            dir: dir.to_path_buf(),
+            config,
+            topics: Arc::new(RwLock::new(HashMap::new())),
+            partitioner: Arc::new(HashPartitioner::new()),
+            offsets: Arc::new(offsets),
+            coordinator: Arc::new(GroupCoordinator::new()),
+            on_sync_error: Arc::new(RwLock::new(None))
+        };
+
+        // Produces only check the interval as they append, so a thread syncs what they leave behind
+        if let SyncPolicy::Interval(interval) = kafka.config.sync_policy {
+            spawn_flusher(Arc::downgrade(&kafka.topics), kafka.on_sync_error.clone(), interval);
+        }
+        coordinator::spawn_expirer(&kafka.coordinator);
 
-        let topics = HashMap::new();
-        let kafka = Kafka { dir: dir.to_path_buf(), topics: topics };
         Ok(kafka)
     }
 
-    fn open(&mut self) -> io::Result<()> {
-        for entry in try!(fs::read_dir(&self.dir)) {
-            let entry = try!(entry);
+    // Replaces the coordinator that manages group membership, such as to pick another assignment
+    // strategy. Handles cloned before keep the old one.
+    pub fn with_coordinator(mut self, coordinator: GroupCoordinator) -> Kafka {
+        for (topic_name, partitions) in self.topics_read().iter() {
+            coordinator.set_partitions(topic_name, partitions.len() as u32);
+        }
+
+        self.coordinator = Arc::new(coordinator);
+        coordinator::spawn_expirer(&self.coordinator);
+        self
+    }
+
+    // Calls `handler` with each error the background syncs of topics synced on an interval run
+    // into, for every handle to the topics. Errors are dropped without one, and the sync is
+    // retried on the next pass either way.
+    pub fn with_sync_error_handler<F>(self, handler: F) -> Kafka where F: Fn(&str, u32, &Error) + Send + Sync + 'static {
+        *self.on_sync_error.write().unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(Arc::new(handler));
+        self
+    }
+
+    // Sets how producers without an explicit partition pick one. Handles cloned before keep the old one.
+    pub fn with_partitioner<P: Partitioner + 'static>(mut self, partitioner: P) -> Kafka {
+        self.partitioner = Arc::new(partitioner);
+        self
+    }
+
+    // Loads the topics already on disk
+    pub fn open(&self) -> Result<()> {
+        let mut topics = self.topics_write();
+
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
             let path = entry.path();
 
-            if path.is_dir() {
-                let topic_name = path.file_name().unwrap().to_str().unwrap().to_string();
-                println!("Found topic: {:?}", topic_name);
-                let topic = Topic::new(&path, BUFFER_SIZE).unwrap();
-                self.topics.insert(topic_name, topic);
+            if !path.is_dir() || path.file_name() == Some(CONSUMER_OFFSETS_TOPIC.as_ref()) {
+                continue;
+            }
+
+            if let Some(topic_name) = path.file_name().and_then(|n| n.to_str()) {
+                let partitions = open_partitions(&path, &self.config)?;
+                self.coordinator.set_partitions(topic_name, partitions.len() as u32);
+                topics.insert(topic_name.to_string(), partitions);
             }
         }
 
         Ok(())
     }
 
-    fn close(&mut self) {
-        for topic in self.topics.values_mut() {
-            topic.close();
+    // Creates a topic with `num_partitions` partitions
+    pub fn create_topic(&self, topic_name: &str, num_partitions: u32) -> Result<()> {
+        if num_partitions == 0 {
+            return Err(Error::InvalidPartitionCount(num_partitions));
+        }
+        check_topic_name(topic_name)?;
+
+        let mut topics = self.topics_write();
+        if topics.contains_key(topic_name) {
+            return Err(Error::TopicExists(topic_name.to_string()));
+        }
+
+        let partitions = create_partitions(&self.dir.join(topic_name), &self.config, num_partitions)?;
+        self.coordinator.set_partitions(topic_name, num_partitions);
+        topics.insert(topic_name.to_string(), partitions);
+        Ok(())
+    }
+
+    pub fn topic_names(&self) -> Vec<String> {
+        self.topics_read().keys().cloned().collect()
+    }
+
+    pub fn num_partitions(&self, topic_name: &str) -> Result<u32> {
+        Ok(self.partitions(topic_name)?.len() as u32)
+    }
+
+    pub fn recovery_reports(&self) -> Vec<RecoveryReport> {
+        self.all_partitions().iter().filter_map(|partition| partition.read().recovery_report().cloned()).collect()
+    }
+
+    // Syncs and closes every topic
+    pub fn close(&self) -> Result<()> {
+        for partition in self.all_partitions() {
+            partition.write().close()?;
         }
+
+        self.offsets.close()
     }
 
-    fn produce(&mut self, topic_name: &str, message: &[u8]) -> Result<(), &'static str> {
-        let base_dir = &self.dir;
-        let topic = self.topics.entry(topic_name.to_string()).or_insert_with(|| {
-            let mut path = PathBuf::from(base_dir);
-            path.push(topic_name);
+    // Appends the message to the topic, creating the topic if needed. The partitioner picks the
+    // partition, and the message is synced to disk according to the topic's sync policy.
+    pub fn produce(&self, topic_name: &str, message: &[u8]) -> Result<RecordMetadata> {
+        self.produce_record(topic_name, &Record::new(message))
+    }
 
-            return Topic::new(&path, BUFFER_SIZE).unwrap();
-        });
+    // Appends the messages to the topic with a single write to each partition they go to,
+    // returning where each was written
+    pub fn produce_batch(&self, topic_name: &str, messages: &[&[u8]]) -> Result<Vec<RecordMetadata>> {
+        self.producer(topic_name)?.send_batch(messages)
+    }
 
-        return topic.produce(message);
+    // Appends the record to the topic, creating the topic if needed
+    pub fn produce_record(&self, topic_name: &str, record: &Record) -> Result<RecordMetadata> {
+        self.producer(topic_name)?.send_record(record)
     }
 
-    fn seek(&self, topic: &str) -> Result<(), &'static str> {
-        Result::Ok(())
+    pub fn seek(&self, topic_name: &str, partition: u32, offset: Offset) -> Result<()> {
+        self.partition(topic_name, partition)?.seek(offset)
     }
 
-    fn consume(&self, topic: &str) -> Option<Vec<u8>> {
-        Option::None
+    // Reads the next message from the partition's shared cursor
+    pub fn consume(&self, topic_name: &str, partition: u32) -> Result<Option<Message>> {
+        self.partition(topic_name, partition)?.consume()
     }
+
+    // Like `consume`, but waits up to `timeout` for a message to be produced if there is none yet
+    pub fn consume_timeout(&self, topic_name: &str, partition: u32, timeout: Duration) -> Result<Option<Message>> {
+        let partition = self.partition(topic_name, partition)?;
+        let deadline = Instant::now() + timeout;
+        let appends = partition.read().appends();
+
+        loop {
+            // Taken before consuming, so an append made meanwhile ends the wait
+            let next_offset = partition.read().next_offset();
+            if let Some(message) = partition.consume()? {
+                return Ok(Some(message));
+            }
+
+            if !appends.wait_for(next_offset, deadline) {
+                return Ok(None);
+            }
+        }
+    }
+
+    // Consumes messages until their keys and payloads add up to at least `min_bytes`, waiting up to
+    // `max_wait` for more to be produced. Returns whatever was consumed once the wait is up, which
+    // may be nothing. No more messages are consumed once `max_bytes` is reached, though the first
+    // always is, whatever its size.
+    pub fn fetch(&self, topic_name: &str, partition: u32, min_bytes: usize, max_bytes: usize, max_wait: Duration) -> Result<Vec<Message>> {
+        let partition = self.partition(topic_name, partition)?;
+        let deadline = Instant::now() + max_wait;
+        let appends = partition.read().appends();
+        let mut messages = Vec::new();
+        let mut num_bytes = 0;
+
+        loop {
+            let next_offset = partition.read().next_offset();
+            while messages.is_empty() || num_bytes < max_bytes {
+                match partition.consume()? {
+                    Some(message) => {
+                        num_bytes += message.num_bytes();
+                        messages.push(message);
+                    },
+                    None => break,
+                }
+            }
+
+            if num_bytes >= min_bytes || num_bytes >= max_bytes || !appends.wait_for(next_offset, deadline) {
+                return Ok(messages);
+            }
+        }
+    }
+
+    // Deletes the sealed segments of every topic with the delete cleanup policy that have fallen
+    // outside the topic's retention limits, returning how many were deleted
+    pub fn enforce_retention(&self) -> Result<usize> {
+        let mut num_deleted = 0;
+        for partition in self.all_partitions() {
+            num_deleted += partition.write().enforce_retention()?;
+        }
+
+        Ok(num_deleted)
+    }
+
+    // Compacts every topic with the compact cleanup policy, returning how many messages were removed
+    pub fn compact(&self) -> Result<u64> {
+        let mut num_removed = 0;
+        for partition in self.all_partitions() {
+            let is_compacted = partition.read().config().cleanup_policy == CleanupPolicy::Compact;
+            if is_compacted {
+                num_removed += partition.compact()?;
+            }
+        }
+
+        Ok(num_removed + self.offsets.compact()?)
+    }
+
+    // The first offset the partition still holds, which moves forward as retention deletes segments
+    pub fn log_start_offset(&self, topic_name: &str, partition: u32) -> Result<Offset> {
+        Ok(self.partition(topic_name, partition)?.read().log_start_offset())
+    }
+
+    // Offset the next message produced to the partition gets
+    pub fn next_offset(&self, topic_name: &str, partition: u32) -> Result<Offset> {
+        Ok(self.partition(topic_name, partition)?.read().next_offset())
+    }
+
+    // Positions the partition's cursor at the first message produced at or after `time`, returning its offset
+    pub fn seek_to_timestamp(&self, topic_name: &str, partition: u32, time: SystemTime) -> Result<Offset> {
+        let partition = self.partition(topic_name, partition)?;
+        let offset = partition.read().offset_for_timestamp(record::to_millis(time))?;
+        partition.seek(offset)?;
+        Ok(offset)
+    }
+
+    pub fn producer(&self, topic_name: &str) -> Result<Producer> {
+        Ok(Producer::new(topic_name, self.topic_or_create(topic_name)?, self.partitioner.clone()))
+    }
+
+    // A consumer of the partition with its own cursor, starting from the first message the
+    // partition still holds
+    pub fn consumer(&self, topic_name: &str, partition: u32) -> Result<Consumer> {
+        Consumer::new(self.partition(topic_name, partition)?)
+    }
+
+    // Durably records that `group` has consumed the partition up to, but not including, `offset`
+    pub fn commit(&self, group: &str, topic_name: &str, partition: u32, offset: Offset) -> Result<()> {
+        if offset > self.partition(topic_name, partition)?.read().next_offset() {
+            return Err(Error::OffsetOutOfRange(offset));
+        }
+
+        self.offsets.commit(group, topic_name, partition, offset)
+    }
+
+    // The offset `group` last committed for the partition
+    pub fn committed(&self, group: &str, topic_name: &str, partition: u32) -> Option<Offset> {
+        self.offsets.committed(group, topic_name, partition)
+    }
+
+    // A consumer of the partition for `group`, resuming from where the group last committed
+    pub fn group_consumer(&self, group: &str, topic_name: &str, partition: u32) -> Result<Consumer> {
+        Consumer::in_group(self.partition(topic_name, partition)?, topic_name, partition, self.offsets.clone(), group)
+    }
+
+    // Tracks consumer group membership, sharing the partitions of the topics between the members
+    pub fn coordinator(&self) -> &GroupCoordinator {
+        &self.coordinator
+    }
+
+    fn partitions(&self, topic_name: &str) -> Result<Vec<Arc<Partition>>> {
+        match self.topics_read().get(topic_name) {
+            Some(partitions) => Ok(partitions.clone()),
+            None => Err(Error::TopicNotFound(topic_name.to_string())),
+        }
+    }
+
+    fn partition(&self, topic_name: &str, partition: u32) -> Result<Arc<Partition>> {
+        let partitions = self.partitions(topic_name)?;
+        partitions.get(partition as usize).cloned().ok_or_else(|| Error::PartitionNotFound { topic: topic_name.to_string(), partition })
+    }
+
+    fn all_partitions(&self) -> Vec<Arc<Partition>> {
+        self.topics_read().values().flatten().cloned().collect()
+    }
+
+    pub(crate) fn topic_or_create(&self, topic_name: &str) -> Result<Vec<Arc<Partition>>> {
+        if let Some(partitions) = self.topics_read().get(topic_name) {
+            return Ok(partitions.clone());
+        }
+        check_topic_name(topic_name)?;
+
+        match self.topics_write().entry(topic_name.to_string()) {
+            Entry::Occupied(entry) => Ok(entry.get().clone()),
+            Entry::Vacant(entry) => {
+                let partitions = create_partitions(&self.dir.join(topic_name), &self.config, self.config.num_partitions)?;
+                self.coordinator.set_partitions(topic_name, partitions.len() as u32);
+                Ok(entry.insert(partitions).clone())
+            }
+        }
+    }
+
+    fn topics_read(&self) -> RwLockReadGuard<'_, HashMap<String, Vec<Arc<Partition>>>> {
+        self.topics.read().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn topics_write(&self) -> RwLockWriteGuard<'_, HashMap<String, Vec<Arc<Partition>>>> {
+        self.topics.write().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+// The offsets store keeps its topic in the same directory, where it would be mistaken for this one
+fn check_topic_name(topic_name: &str) -> Result<()> {
+    if topic_name == CONSUMER_OFFSETS_TOPIC {
+        return Err(Error::ReservedTopicName(topic_name.to_string()));
+    }
+
+    Ok(())
+}
+
+// Syncs every partition with unsynced messages each `interval`, until the handles to the topics are dropped.
+// A failed sync leaves the messages unsynced, so it is retried on the next pass, and is reported to
+// the sync error handler if there is one.
+fn spawn_flusher(topics: Weak<RwLock<HashMap<String, Vec<Arc<Partition>>>>>, on_error: Arc<RwLock<Option<Arc<SyncErrorHandler>>>>, interval: Duration) {
+    let interval = interval.max(MIN_FLUSH_INTERVAL);
+
+    thread::spawn(move || {
+        loop {
+            thread::sleep(interval);
+
+            let partitions: Vec<(String, u32, Arc<Partition>)> = match topics.upgrade() {
+                Some(topics) => topics.read().unwrap_or_else(|poisoned| poisoned.into_inner()).iter()
+                    .flat_map(|(topic_name, partitions)| {
+                        partitions.iter().enumerate().map(move |(id, partition)| (topic_name.clone(), id as u32, partition.clone()))
+                    })
+                    .collect(),
+                None => return,
+            };
+
+            for (topic_name, id, partition) in partitions {
+                let unsynced = {
+                    let topic = partition.read();
+                    topic.durable_offset() < topic.next_offset()
+                };
+                if !unsynced {
+                    continue;
+                }
+
+                if let Err(e) = partition.sync() {
+                    let handler = on_error.read().unwrap_or_else(|poisoned| poisoned.into_inner()).clone();
+                    if let Some(handler) = handler {
+                        handler(&topic_name, id, &e);
+                    }
+                }
+            }
+        }
+    });
+}
+
+// Each partition of a topic keeps its segments in a `partition_<n>` directory within the topic's
+fn partition_dir(topic_dir: &Path, partition: u32) -> PathBuf {
+    topic_dir.join(format!("partition_{}", partition))
+}
+
+fn create_partitions(topic_dir: &Path, config: &TopicConfig, num_partitions: u32) -> Result<Vec<Arc<Partition>>> {
+    (0..num_partitions)
+        .map(|partition| Ok(Arc::new(Partition::new(Topic::new(&partition_dir(topic_dir, partition), config.clone())?))))
+        .collect()
+}
+
+fn open_partitions(topic_dir: &Path, config: &TopicConfig) -> Result<Vec<Arc<Partition>>> {
+    let mut num_partitions = 0;
+    let mut has_segments = false;
+
+    for entry in fs::read_dir(topic_dir)? {
+        let path = entry?.path();
+        let file_name = match path.file_name().and_then(|n| n.to_str()) {
+            Some(file_name) => file_name.to_string(),
+            None => continue,
+        };
+
+        if path.is_dir() && file_name.starts_with("partition_") {
+            if let Ok(partition) = file_name.replace("partition_", "").parse::<u32>() {
+                num_partitions = num_partitions.max(partition + 1);
+            }
+        } else if file_name.starts_with("segment_") {
+            has_segments = true;
+        }
+    }
+
+    // Topics written before they had partitions keep their segments in the topic directory
+    if num_partitions == 0 && has_segments {
+        return Ok(vec![Arc::new(Partition::new(Topic::new(topic_dir, config.clone())?))]);
+    }
+
+    create_partitions(topic_dir, config, num_partitions.max(1))
 }
 
 #[cfg(test)]
 mod tests {
     use std::path::Path;
     use super::*;
+    use config::{Acks, SyncPolicy, DEFAULT_BLOCK_SIZE as BUFFER_SIZE};
+    use index;
+    use segment::{self, SegmentHeader, SegmentReader, SEGMENT_HEADER_BYTES, SEGMENT_VERSION};
     use super::Kafka;
+    use assignor::{RoundRobinStrategy, TopicPartition};
+    use partitioner::RoundRobinPartitioner;
     use std::fs;
-    use std::time::{Duration, SystemTime};
+    use std::thread;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
     use std::io;
 
@@ -80,11 +459,14 @@ mod tests {
 
     #[test]
     fn test_open () {
+        fs::remove_dir_all("./test_data/test_open");
+        fs::create_dir_all("./test_data/test_open/foo");
+
         let path = Path::new("./test_data/test_open");
-        let mut kafka = Kafka::new(&path).unwrap();
+        let kafka = Kafka::new(path).unwrap();
         assert!(kafka.open().is_ok());
 
-        let topics: Vec<&String> = kafka.topics.keys().collect();
+        let topics = kafka.topic_names();
         assert_eq!(topics, vec!["foo"]);
     }
 
@@ -94,21 +476,1017 @@ mod tests {
 
         let path = Path::new("./test_data/test_produce");
 
-        let mut kafka = Kafka::new(&path).unwrap();
+        let kafka = Kafka::new(path).unwrap();
         assert!(kafka.open().is_ok());
 
-        let result = kafka.produce("foo", &vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
-        assert!(result.is_ok());
+        let result = kafka.produce("foo", &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
+        assert_eq!(result.unwrap().offset, 0);
+
+        let second_result = kafka.produce("foo", &[10, 11, 12, 13, 14, 15, 16, 17, 18, 19]);
+        assert_eq!(second_result.unwrap().offset, 1);
+    }
+
+    #[test]
+    fn test_consume () {
+        let path = Path::new("./test_data/test_consume");
+        let kafka = init_kafka_for_test(path);
+
+        let large_message = vec![7; BUFFER_SIZE * 2];
+        kafka.produce("foo", &[0, 1, 2]).unwrap();
+        kafka.produce("foo", &large_message).unwrap();
+        kafka.produce("foo", &[3, 4, 5]).unwrap();
+
+        assert_eq!(consume_payload(&kafka, "foo"), Some(vec![0, 1, 2]));
+        assert_eq!(consume_payload(&kafka, "foo"), Some(large_message));
+        assert_eq!(consume_payload(&kafka, "foo"), Some(vec![3, 4, 5]));
+        assert_eq!(consume_payload(&kafka, "foo"), None);
+
+        kafka.produce("foo", &[6, 7, 8]).unwrap();
+        let message = kafka.consume("foo", 0).unwrap().unwrap();
+        assert_eq!(message.offset, 3);
+        assert_eq!(message.payload, vec![6, 7, 8]);
+
+        assert!(kafka.consume("bar", 0).is_err());
+    }
+
+    #[test]
+    fn test_seek () {
+        let path = Path::new("./test_data/test_seek");
+        let kafka = init_kafka_for_test(path);
+
+        for i in 0..10 {
+            kafka.produce("foo", &[i]).unwrap();
+        }
+
+        kafka.seek("foo", 0, 7).unwrap();
+        assert_eq!(consume_payload(&kafka, "foo"), Some(vec![7]));
+
+        kafka.seek("foo", 0, 0).unwrap();
+        assert_eq!(consume_payload(&kafka, "foo"), Some(vec![0]));
+
+        assert!(kafka.seek("foo", 0, 10).is_ok());
+
+        match kafka.seek("foo", 0, 11) {
+            Err(Error::OffsetOutOfRange(11)) => (),
+            _ => panic!("Expected offset out of range"),
+        }
+
+        match kafka.seek("bar", 0, 0) {
+            Err(Error::TopicNotFound(ref topic_name)) if topic_name == "bar" => (),
+            _ => panic!("Expected topic not found"),
+        }
+    }
+
+    #[test]
+    fn test_seek_with_index () {
+        let path = Path::new("./test_data/test_seek_with_index");
+        let config = TopicConfig { segment_messages: Some(50), index_interval_bytes: 256, ..TopicConfig::default() };
+        let kafka = init_kafka_with_config_for_test(path, config.clone());
+
+        for i in 0..100 {
+            kafka.produce("foo", &[i; 40]).unwrap();
+        }
+        kafka.close();
+
+        // The sealed segment's index gets rebuilt
+        let index_path = path.join("foo/partition_0/segment_000000000.index");
+        let index_len = fs::metadata(&index_path).unwrap().len();
+        fs::remove_file(&index_path).unwrap();
+
+        let kafka = Kafka::with_config(path, config).unwrap();
+        kafka.open().unwrap();
+        assert_eq!(fs::metadata(&index_path).unwrap().len(), index_len);
+        assert!(index_len > 16);
+
+        for &offset in &[0, 33, 49, 50, 77, 99] {
+            kafka.seek("foo", 0, offset).unwrap();
+            let message = kafka.consume("foo", 0).unwrap().unwrap();
+            assert_eq!(message.offset, offset);
+            assert_eq!(message.payload, vec![offset as u8; 40]);
+        }
+    }
+
+    #[test]
+    fn test_seek_to_timestamp () {
+        let path = Path::new("./test_data/test_seek_to_timestamp");
+        let config = TopicConfig { segment_messages: Some(10), index_interval_bytes: 64, ..TopicConfig::default() };
+        let kafka = init_kafka_with_config_for_test(path, config.clone());
+
+        // Timestamps go up by 10ms per message, apart from one that arrives late
+        for i in 0..30 {
+            let timestamp = if i == 15 { 1_000 } else { 1_000 + i * 10 };
+            kafka.produce_record("foo", &Record::new(vec![i as u8; 20]).with_timestamp(timestamp)).unwrap();
+        }
+        kafka.close();
+
+        fs::remove_file(path.join("foo/partition_0/segment_000000000.timeindex")).unwrap();
+        let kafka = Kafka::with_config(path, config).unwrap();
+        kafka.open().unwrap();
+
+        let at = |millis| UNIX_EPOCH + Duration::from_millis(millis);
+        assert_eq!(kafka.seek_to_timestamp("foo", 0, at(0)).unwrap(), 0);
+        assert_eq!(kafka.seek_to_timestamp("foo", 0, at(1_000)).unwrap(), 0);
+        assert_eq!(kafka.seek_to_timestamp("foo", 0, at(1_055)).unwrap(), 6);
+        assert_eq!(kafka.seek_to_timestamp("foo", 0, at(1_150)).unwrap(), 16);
+        assert_eq!(kafka.seek_to_timestamp("foo", 0, at(1_290)).unwrap(), 29);
+        assert_eq!(kafka.seek_to_timestamp("foo", 0, at(5_000)).unwrap(), 30);
+
+        kafka.seek_to_timestamp("foo", 0, at(1_201)).unwrap();
+        assert_eq!(kafka.consume("foo", 0).unwrap().unwrap().offset, 21);
 
-        let second_result = kafka.produce("foo", &vec![10, 11, 12, 13, 14, 15, 16, 17, 18, 19]);
-        assert!(second_result.is_ok());
+        let mut consumer = kafka.consumer("foo", 0).unwrap();
+        assert_eq!(consumer.seek_to_timestamp(at(1_100)).unwrap(), 10);
+        assert_eq!(consumer.poll().unwrap().unwrap().timestamp, 1_100);
+    }
+
+    #[test]
+    fn test_retention_by_bytes () {
+        let path = Path::new("./test_data/test_retention_by_bytes");
+        let config = TopicConfig { segment_messages: Some(4), retention_bytes: Some(3 * 1100), ..TopicConfig::default() };
+        let kafka = init_kafka_with_config_for_test(path, config.clone());
+
+        for i in 0..20 {
+            kafka.produce("foo", &[i; 200]).unwrap();
+        }
+        assert_eq!(kafka.partition("foo", 0).unwrap().read().num_segments(), 5);
+        assert_eq!(kafka.log_start_offset("foo", 0).unwrap(), 0);
+        kafka.close();
+
+        // Sizes of sealed segments come from their files after a restart
+        let kafka = Kafka::with_config(path, config).unwrap();
+        kafka.open().unwrap();
+        assert_eq!(kafka.enforce_retention().unwrap(), 2);
+        assert_eq!(kafka.enforce_retention().unwrap(), 0);
+
+        assert_eq!(kafka.partition("foo", 0).unwrap().read().num_segments(), 3);
+        assert_eq!(kafka.log_start_offset("foo", 0).unwrap(), 8);
+        assert!(calculate_dir_size(&path.join("foo")).unwrap() <= 3 * 1100 + 3 * (16 + 16));
+        assert!(!path.join("foo/partition_0/segment_000000004").exists());
+        assert!(!path.join("foo/partition_0/segment_000000004.index").exists());
+        assert!(!path.join("foo/partition_0/segment_000000004.timeindex").exists());
+
+        match kafka.seek("foo", 0, 7) {
+            Err(Error::OffsetOutOfRange(7)) => (),
+            _ => panic!("Expected offset out of range"),
+        }
+
+        // A consume from a deleted segment fails rather than skipping the messages lost with it
+        match kafka.consume("foo", 0) {
+            Err(Error::OffsetOutOfRange(0)) => (),
+            _ => panic!("Expected offset out of range"),
+        }
+        kafka.seek("foo", 0, 8).unwrap();
+        assert_eq!(consume_payload(&kafka, "foo"), Some(vec![8; 200]));
+        assert_eq!(kafka.consumer("foo", 0).unwrap().poll().unwrap().unwrap().offset, 8);
+    }
+
+    #[test]
+    fn test_retention_by_age () {
+        let path = Path::new("./test_data/test_retention_by_age");
+        let config = TopicConfig { segment_messages: Some(2), retention_age: Some(Duration::from_secs(60)), ..TopicConfig::default() };
+        let kafka = init_kafka_with_config_for_test(path, config);
+
+        let old = record::now_millis() - 120 * 1000;
+        for i in 0..5 {
+            kafka.produce_record("foo", &Record::new(vec![i]).with_timestamp(old)).unwrap();
+        }
+        kafka.produce("foo", &[5]).unwrap();
+        kafka.produce("foo", &[6]).unwrap();
+
+        // The segment holding offsets 4 and 5 has a recent message, so it and everything after it stays
+        assert_eq!(kafka.enforce_retention().unwrap(), 2);
+        assert_eq!(kafka.log_start_offset("foo", 0).unwrap(), 4);
+
+        kafka.produce("foo", &[7]).unwrap();
+        assert_eq!(kafka.partition("foo", 0).unwrap().read().num_segments(), 2);
+    }
+
+    #[test]
+    fn test_retention_keeps_segments_without_timestamps () {
+        let path = Path::new("./test_data/test_retention_keeps_segments_without_timestamps");
+        let config = TopicConfig { segment_messages: Some(1), cleanup_policy: CleanupPolicy::Compact, ..TopicConfig::default() };
+        let kafka = init_kafka_with_config_for_test(path, config.clone());
+
+        // Compaction empties the first segment, leaving it without a newest message
+        for record in &[Record::new("a0").with_key("a"), Record::new("a1").with_key("a"), Record::new("b0").with_key("b")] {
+            kafka.produce_record("foo", record).unwrap();
+        }
+        assert_eq!(kafka.compact().unwrap(), 1);
+        kafka.close().unwrap();
+
+        let config = TopicConfig { cleanup_policy: CleanupPolicy::Delete, retention_age: Some(Duration::from_secs(60)), ..config };
+        let kafka = Kafka::with_config(path, config).unwrap();
+        kafka.open().unwrap();
+        assert_eq!(kafka.enforce_retention().unwrap(), 0);
+        assert_eq!(kafka.partition("foo", 0).unwrap().read().num_segments(), 3);
+    }
+
+    #[test]
+    fn test_compaction () {
+        let path = Path::new("./test_data/test_compaction");
+        let config = TopicConfig {
+            segment_messages: Some(3),
+            cleanup_policy: CleanupPolicy::Compact,
+            delete_retention: Duration::from_secs(60),
+            ..TopicConfig::default()
+        };
+        let kafka = init_kafka_with_config_for_test(path, config.clone());
+
+        let old = record::now_millis() - 120 * 1000;
+        let records = vec![
+            Record::new("a0").with_key("a"),
+            Record::new("b0").with_key("b"),
+            Record::new("x"),
+            Record::new("a1").with_key("a"),
+            Record::new("c0").with_key("c"),
+            Record::tombstone("b").with_timestamp(old),
+            Record::tombstone("c"),
+            Record::new("a2").with_key("a"),
+            Record::new("d0").with_key("d"),
+            Record::new("e0").with_key("e"),
+        ];
+        for record in &records {
+            kafka.produce_record("foo", record).unwrap();
+        }
+
+        // Tombstones expire by when their segment was sealed, not by their own timestamps
+        let sealed = SystemTime::now() - Duration::from_secs(120);
+        let segment_path = path.join("foo/partition_0/segment_000000006");
+        fs::OpenOptions::new().write(true).open(&segment_path).unwrap().set_modified(sealed).unwrap();
+
+        // Readers already part way through a segment keep reading the old file
+        let mut reader = SegmentReader::open(&path.join("foo/partition_0/segment_000000000")).unwrap();
+        assert_eq!(reader.next().unwrap().unwrap().offset, 0);
+
+        assert_eq!(kafka.enforce_retention().unwrap(), 0);
+        assert_eq!(kafka.compact().unwrap(), 5);
+        assert_eq!(kafka.compact().unwrap(), 0);
+        assert_eq!(reader.count(), 2);
+        assert_eq!(fs::metadata(&segment_path).unwrap().modified().unwrap(), sealed);
+
+        kafka.close();
+        let kafka = Kafka::with_config(path, config).unwrap();
+        kafka.open().unwrap();
+
+        let messages: Vec<Message> = (0..5).map(|_| kafka.consume("foo", 0).unwrap().unwrap()).collect();
+        let offsets: Vec<Offset> = messages.iter().map(|m| m.offset).collect();
+        assert_eq!(offsets, vec![2, 5, 7, 8, 9]);
+        assert!(messages[1].tombstone);
+        assert_eq!(messages[1].key, Some(b"b".to_vec()));
+        assert_eq!(messages[2].payload, b"a2");
+        assert!(kafka.consume("foo", 0).unwrap().is_none());
+
+        kafka.seek("foo", 0, 3).unwrap();
+        assert_eq!(kafka.consume("foo", 0).unwrap().unwrap().offset, 5);
+        assert!(!path.join("foo/partition_0/cleaned_000000000").exists());
+    }
+
+    #[test]
+    fn test_produce_during_compaction () {
+        let path = Path::new("./test_data/test_produce_during_compaction");
+        let config = TopicConfig { segment_messages: Some(2), cleanup_policy: CleanupPolicy::Compact, ..TopicConfig::default() };
+        let kafka = init_kafka_with_config_for_test(path, config);
+
+        for key in &["a", "a", "b"] {
+            kafka.produce_record("foo", &Record::new(*key).with_key(*key)).unwrap();
+        }
+
+        // The cleaned segments are written without the partition locked, then swapped in
+        let partition = kafka.partition("foo", 0).unwrap();
+        let cleaned = partition.read().start_compaction().unwrap().clean().unwrap();
+        for key in &["b", "c", "c"] {
+            kafka.produce_record("foo", &Record::new(*key).with_key(*key)).unwrap();
+        }
+        assert_eq!(partition.write().finish_compaction(cleaned).unwrap(), 1);
+
+        let offsets: Vec<Offset> = (0..5).map(|_| kafka.consume("foo", 0).unwrap().unwrap().offset).collect();
+        assert_eq!(offsets, vec![1, 2, 3, 4, 5]);
+
+        // The segment sealed meanwhile is cleaned by the next compaction
+        assert_eq!(kafka.compact().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_consumer_group_commit () {
+        let path = Path::new("./test_data/test_consumer_group_commit");
+        let config = TopicConfig { segment_messages: Some(2), ..TopicConfig::default() };
+        let kafka = init_kafka_with_config_for_test(path, config.clone());
+
+        for i in 0..5 {
+            kafka.produce("foo", &[i]).unwrap();
+        }
+
+        {
+            let mut consumer = kafka.group_consumer("workers", "foo", 0).unwrap();
+            assert_eq!(consumer.position(), 0);
+            consumer.poll().unwrap();
+            consumer.poll().unwrap();
+            consumer.commit().unwrap();
+
+            assert!(kafka.consumer("foo", 0).unwrap().commit().is_err());
+        }
+
+        assert_eq!(kafka.committed("workers", "foo", 0), Some(2));
+        assert_eq!(kafka.committed("others", "foo", 0), None);
+        assert!(kafka.commit("workers", "foo", 0, 6).is_err());
+        assert!(kafka.commit("workers", "missing", 0, 0).is_err());
+
+        kafka.commit("workers", "foo", 0, 3).unwrap();
+        kafka.commit("others", "foo", 0, 1).unwrap();
+        kafka.commit("workers", "foo", 0, 4).unwrap();
+
+        kafka.close().unwrap();
+        let kafka = Kafka::with_config(path, config).unwrap();
+        kafka.open().unwrap();
+
+        assert_eq!(kafka.topic_names(), vec!["foo"]);
+        assert_eq!(kafka.committed("workers", "foo", 0), Some(4));
+        assert_eq!(kafka.committed("others", "foo", 0), Some(1));
+
+        let mut consumer = kafka.group_consumer("workers", "foo", 0).unwrap();
+        assert_eq!(consumer.poll().unwrap().unwrap().payload, vec![4]);
+        assert!(consumer.poll().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_group_membership () {
+        let path = Path::new("./test_data/test_group_membership");
+        fs::remove_dir_all(path);
+        let coordinator = GroupCoordinator::new().with_strategy(RoundRobinStrategy);
+        let kafka = Kafka::new(path).unwrap().with_coordinator(coordinator);
+        kafka.open().unwrap();
+
+        kafka.produce("foo", &[0]).unwrap();
+        kafka.produce("bar", &[1]).unwrap();
+
+        assert_eq!(kafka.coordinator().join("workers", "a", &["foo", "bar", "baz"], None).len(), 2);
+        assert_eq!(kafka.coordinator().join("workers", "b", &["foo", "bar", "baz"], None), vec![TopicPartition::new("foo", 0)]);
+        assert_eq!(kafka.coordinator().assignment("workers", "a").unwrap(), vec![TopicPartition::new("bar", 0)]);
+
+        // Topics created later are shared out too
+        kafka.produce("baz", &[2]).unwrap();
+        assert_eq!(kafka.coordinator().assignment("workers", "a").unwrap(), vec![TopicPartition::new("bar", 0), TopicPartition::new("foo", 0)]);
+        assert_eq!(kafka.coordinator().assignment("workers", "b").unwrap(), vec![TopicPartition::new("baz", 0)]);
+    }
+
+    #[test]
+    fn test_partitioned_topic () {
+        let path = Path::new("./test_data/test_partitioned_topic");
+        let kafka = init_kafka_for_test(path);
+        kafka.create_topic("foo", 3).unwrap();
+        assert!(kafka.create_topic("foo", 3).is_err());
+
+        let mut placed = Vec::new();
+        for i in 0..6 {
+            let key = if i % 2 == 0 { "even" } else { "odd" };
+            placed.push(kafka.produce_record("foo", &Record::new(vec![i]).with_key(key)).unwrap());
+        }
+        let explicit = kafka.produce_record("foo", &Record::new(vec![6]).with_partition(2)).unwrap();
+        assert_eq!(explicit.partition, 2);
+        match kafka.produce_record("foo", &Record::new(vec![7]).with_partition(3)) {
+            Err(Error::PartitionNotFound { partition: 3, .. }) => (),
+            _ => panic!("Expected partition not found"),
+        }
+
+        // Records with the same key land on the same partition, in the order they were produced
+        let placed: Vec<(u32, Offset)> = placed.iter().map(|m| (m.partition, m.offset)).collect();
+        assert_eq!(placed, vec![(2, 0), (1, 0), (2, 1), (1, 1), (2, 2), (1, 2)]);
+
+        kafka.close().unwrap();
+        let kafka = Kafka::new(path).unwrap();
+        kafka.open().unwrap();
+        assert_eq!(kafka.num_partitions("foo").unwrap(), 3);
+
+        let mut consumer = kafka.consumer("foo", 2).unwrap();
+        let mut payloads = Vec::new();
+        while let Some(message) = consumer.poll().unwrap() {
+            payloads.push(message.payload[0]);
+        }
+        assert_eq!(payloads, vec![0, 2, 4, 6]);
+        assert!(kafka.consumer("foo", 0).unwrap().poll().unwrap().is_none());
+        assert!(kafka.consumer("foo", 3).is_err());
+    }
+
+    #[test]
+    fn test_zero_partitions () {
+        let path = Path::new("./test_data/test_zero_partitions");
+        let kafka = init_kafka_for_test(path);
+
+        match kafka.create_topic("foo", 0) {
+            Err(Error::InvalidPartitionCount(0)) => (),
+            _ => panic!("Expected invalid partition count"),
+        }
+        assert!(kafka.topic_names().is_empty());
+
+        let config = TopicConfig { num_partitions: 0, ..TopicConfig::default() };
+        match Kafka::with_config(path, config) {
+            Err(Error::InvalidPartitionCount(0)) => (),
+            _ => panic!("Expected invalid partition count"),
+        }
+    }
+
+    #[test]
+    fn test_reserved_topic_name () {
+        let path = Path::new("./test_data/test_reserved_topic_name");
+        let kafka = init_kafka_for_test(path);
+
+        match kafka.create_topic(CONSUMER_OFFSETS_TOPIC, 1) {
+            Err(Error::ReservedTopicName(_)) => (),
+            _ => panic!("Expected reserved topic name"),
+        }
+        match kafka.produce(CONSUMER_OFFSETS_TOPIC, &[1]) {
+            Err(Error::ReservedTopicName(_)) => (),
+            _ => panic!("Expected reserved topic name"),
+        }
+        assert!(kafka.topic_names().is_empty());
+
+        // Committing still works, the offsets being kept apart from the topics
+        kafka.produce("foo", &[1]).unwrap();
+        kafka.commit("workers", "foo", 0, 1).unwrap();
+        assert_eq!(kafka.committed("workers", "foo", 0), Some(1));
+        assert_eq!(kafka.topic_names(), vec!["foo".to_string()]);
+    }
+
+    #[test]
+    fn test_round_robin_partitioner () {
+        let path = Path::new("./test_data/test_round_robin_partitioner");
+        fs::remove_dir_all(path);
+        let config = TopicConfig { num_partitions: 2, ..TopicConfig::default() };
+        let kafka = Kafka::with_config(path, config).unwrap().with_partitioner(RoundRobinPartitioner::new());
+        kafka.open().unwrap();
+
+        let placed = kafka.produce_batch("foo", &[b"a", b"b", b"c"]).unwrap();
+        let placed: Vec<(u32, Offset)> = placed.iter().map(|m| (m.partition, m.offset)).collect();
+        assert_eq!(placed, vec![(0, 0), (1, 0), (0, 1)]);
+        assert_eq!(consume_payload(&kafka, "foo"), Some(b"a".to_vec()));
+        assert_eq!(kafka.consume("foo", 1).unwrap().unwrap().payload, b"b");
+    }
+
+    #[test]
+    fn test_open_unpartitioned_topic () {
+        let path = Path::new("./test_data/test_open_unpartitioned_topic");
+        let kafka = init_kafka_for_test(path);
+        kafka.produce("foo", b"a").unwrap();
+        kafka.close().unwrap();
+
+        // Lay the topic out the way it was before topics had partitions
+        fs::rename(path.join("foo/partition_0"), path.join("old")).unwrap();
+        fs::remove_dir_all(path.join("foo")).unwrap();
+        fs::rename(path.join("old"), path.join("foo")).unwrap();
+
+        let kafka = Kafka::new(path).unwrap();
+        kafka.open().unwrap();
+        assert_eq!(kafka.num_partitions("foo").unwrap(), 1);
+        assert_eq!(kafka.produce("foo", b"b").unwrap().offset, 1);
+        assert_eq!(consume_payload(&kafka, "foo"), Some(b"a".to_vec()));
+        assert!(!path.join("foo/partition_0").exists());
+    }
+
+    #[test]
+    fn test_shared_between_threads () {
+        fn assert_shareable<T: Clone + Send + Sync>() {}
+        assert_shareable::<Kafka>();
+
+        let path = Path::new("./test_data/test_shared_between_threads");
+        let config = TopicConfig { segment_messages: Some(10), ..TopicConfig::default() };
+        let kafka = init_kafka_with_config_for_test(path, config);
+        kafka.create_topic("foo", 1).unwrap();
+
+        let producers: Vec<_> = ["foo", "bar", "baz"].iter().map(|&topic| {
+            let kafka = kafka.clone();
+            thread::spawn(move || {
+                for i in 0..50u8 {
+                    kafka.produce(topic, &[i]).unwrap();
+                }
+            })
+        }).collect();
+
+        // Reads of a partition interleave with the writes to it
+        let mut consumer = kafka.consumer("foo", 0).unwrap();
+        let mut payloads = Vec::new();
+        while payloads.len() < 50 {
+            match consumer.poll().unwrap() {
+                Some(message) => payloads.push(message.payload[0]),
+                None => thread::yield_now(),
+            }
+        }
+
+        for producer in producers {
+            producer.join().unwrap();
+        }
+
+        assert_eq!(payloads, (0..50).collect::<Vec<u8>>());
+        let mut topics = kafka.topic_names();
+        topics.sort();
+        assert_eq!(topics, vec!["bar", "baz", "foo"]);
+        for topic in &topics {
+            assert_eq!(kafka.partition(topic, 0).unwrap().read().next_offset(), 50);
+        }
+    }
+
+    #[test]
+    fn test_tail_active_segment () {
+        let path = Path::new("./test_data/test_tail_active_segment");
+        let config = TopicConfig { block_size: 64, ..TopicConfig::default() };
+        let kafka = init_kafka_with_config_for_test(path, config);
+        kafka.create_topic("foo", 1).unwrap();
+
+        // Messages span several blocks, and batches rewrite the block left part way filled
+        let payloads: Vec<Vec<u8>> = (0..200).map(|i| vec![i as u8; 1 + i % 150]).collect();
+        let producer = {
+            let kafka = kafka.clone();
+            let payloads = payloads.clone();
+            thread::spawn(move || {
+                for batch in payloads.chunks(3) {
+                    let batch: Vec<&[u8]> = batch.iter().map(|payload| payload.as_slice()).collect();
+                    kafka.produce_batch("foo", &batch).unwrap();
+                }
+            })
+        };
+
+        let mut consumer = kafka.consumer("foo", 0).unwrap();
+        let mut consumed = Vec::new();
+        while consumed.len() < payloads.len() {
+            match consumer.poll().unwrap() {
+                Some(message) => consumed.push(message.payload),
+                None => thread::yield_now(),
+            }
+        }
+
+        producer.join().unwrap();
+        assert_eq!(consumed, payloads);
+        assert_eq!(kafka.partition("foo", 0).unwrap().read().num_segments(), 1);
+    }
+
+    #[test]
+    fn test_consume_timeout () {
+        let path = Path::new("./test_data/test_consume_timeout");
+        let kafka = init_kafka_for_test(path);
+        kafka.create_topic("foo", 1).unwrap();
+
+        let start = Instant::now();
+        assert!(kafka.consume_timeout("foo", 0, Duration::from_millis(50)).unwrap().is_none());
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        let producer = {
+            let kafka = kafka.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                kafka.produce("foo", &[1, 2]).unwrap();
+            })
+        };
+
+        let message = kafka.consume_timeout("foo", 0, Duration::from_secs(10)).unwrap().unwrap();
+        assert_eq!(message.payload, vec![1, 2]);
+        producer.join().unwrap();
+
+        let mut consumer = kafka.consumer("foo", 0).unwrap();
+        assert_eq!(consumer.poll_timeout(Duration::from_secs(10)).unwrap().unwrap().payload, vec![1, 2]);
+        assert!(consumer.poll_timeout(Duration::from_millis(20)).unwrap().is_none());
+
+        match kafka.consume_timeout("missing", 0, Duration::from_millis(10)) {
+            Err(Error::TopicNotFound(_)) => (),
+            _ => panic!("Expected topic not found"),
+        }
+    }
+
+    #[test]
+    fn test_consume_past_compacted_tail () {
+        let path = Path::new("./test_data/test_consume_past_compacted_tail");
+        let config = TopicConfig {
+            segment_messages: Some(2),
+            cleanup_policy: CleanupPolicy::Compact,
+            delete_retention: Duration::from_secs(60),
+            ..TopicConfig::default()
+        };
+        let kafka = init_kafka_with_config_for_test(path, config.clone());
+        kafka.produce_record("foo", &Record::new("a0").with_key("a")).unwrap();
+        kafka.produce_record("foo", &Record::tombstone("b")).unwrap();
+        kafka.produce("foo", b"c").unwrap();
+        kafka.close().unwrap();
+
+        // A crash right after rolling leaves an empty active segment, so the sealed segment's
+        // tombstone is the last message in the partition
+        fs::write(path.join("foo/partition_0/segment_000000002"), b"").unwrap();
+        let sealed = SystemTime::now() - Duration::from_secs(120);
+        fs::OpenOptions::new().write(true).open(path.join("foo/partition_0/segment_000000000")).unwrap().set_modified(sealed).unwrap();
+
+        let kafka = Kafka::with_config(path, config).unwrap();
+        kafka.open().unwrap();
+        assert_eq!(kafka.next_offset("foo", 0).unwrap(), 2);
+        assert_eq!(kafka.compact().unwrap(), 1);
+
+        // The cursor moves past the gap the tombstone left, rather than looking for it in the next segment
+        let mut consumer = kafka.consumer("foo", 0).unwrap();
+        assert_eq!(consumer.poll().unwrap().unwrap().offset, 0);
+        assert!(consumer.poll().unwrap().is_none());
+        assert_eq!(consumer.position(), 2);
+
+        // Waiting from within the gap lasts the whole timeout, then ends with the next message produced
+        consumer.seek(1).unwrap();
+        kafka.seek("foo", 0, 1).unwrap();
+        let start = Instant::now();
+        assert!(consumer.poll_timeout(Duration::from_millis(100)).unwrap().is_none());
+        assert!(kafka.consume_timeout("foo", 0, Duration::from_millis(100)).unwrap().is_none());
+        assert!(start.elapsed() >= Duration::from_millis(200));
+
+        let producer = {
+            let kafka = kafka.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                kafka.produce("foo", b"d").unwrap();
+            })
+        };
+
+        let message = kafka.consume_timeout("foo", 0, Duration::from_secs(10)).unwrap().unwrap();
+        assert_eq!((message.offset, message.payload), (2, b"d".to_vec()));
+        assert_eq!(consumer.poll_timeout(Duration::from_secs(10)).unwrap().unwrap().offset, 2);
+        producer.join().unwrap();
+    }
+
+    #[test]
+    fn test_fetch_min_bytes () {
+        let path = Path::new("./test_data/test_fetch_min_bytes");
+        let kafka = init_kafka_for_test(path);
+        kafka.produce("foo", &[0; 4]).unwrap();
+
+        // Whatever there is comes back once the wait is up
+        let messages = kafka.fetch("foo", 0, 100, usize::MAX, Duration::from_millis(20)).unwrap();
+        assert_eq!(messages.len(), 1);
+        assert!(kafka.fetch("foo", 0, 1, usize::MAX, Duration::from_millis(20)).unwrap().is_empty());
+
+        let producer = {
+            let kafka = kafka.clone();
+            thread::spawn(move || {
+                for i in 1..4u8 {
+                    thread::sleep(Duration::from_millis(20));
+                    kafka.produce("foo", &[i; 4]).unwrap();
+                }
+            })
+        };
+
+        let messages = kafka.fetch("foo", 0, 10, usize::MAX, Duration::from_secs(10)).unwrap();
+        let payloads: Vec<Vec<u8>> = messages.into_iter().map(|message| message.payload).collect();
+        assert_eq!(payloads, vec![vec![1; 4], vec![2; 4], vec![3; 4]]);
+        producer.join().unwrap();
+    }
+
+    #[test]
+    fn test_fetch_max_bytes () {
+        let path = Path::new("./test_data/test_fetch_max_bytes");
+        let kafka = init_kafka_for_test(path);
+        kafka.produce("foo", &[0; 8]).unwrap();
+        for i in 1..5u8 {
+            kafka.produce("foo", &[i; 4]).unwrap();
+        }
+
+        // The first message comes back though it's over the limit
+        let messages = kafka.fetch("foo", 0, 1, 6, Duration::from_millis(20)).unwrap();
+        assert_eq!(messages.iter().map(|message| message.offset).collect::<Vec<_>>(), vec![0]);
+
+        // The last message may take the fetch past the limit, and the rest are left for the next one
+        let messages = kafka.fetch("foo", 0, 1, 6, Duration::from_millis(20)).unwrap();
+        assert_eq!(messages.iter().map(|message| message.offset).collect::<Vec<_>>(), vec![1, 2]);
+
+        // Reaching the limit ends the wait for `min_bytes`
+        let messages = kafka.fetch("foo", 0, 100, 6, Duration::from_secs(10)).unwrap();
+        assert_eq!(messages.iter().map(|message| message.offset).collect::<Vec<_>>(), vec![3, 4]);
+
+        // Keys count toward the limits as well as payloads
+        kafka.produce_record("foo", &Record::new([5; 2]).with_key([5; 4])).unwrap();
+        kafka.produce("foo", &[6; 2]).unwrap();
+        let messages = kafka.fetch("foo", 0, 1, 6, Duration::from_millis(20)).unwrap();
+        assert_eq!(messages.iter().map(|message| message.offset).collect::<Vec<_>>(), vec![5]);
+    }
+
+    #[test]
+    fn test_produce_after_reopen () {
+        let path = Path::new("./test_data/test_produce_after_reopen");
+        let kafka = init_kafka_for_test(path);
+
+        let large_message = vec![7; BUFFER_SIZE + 10];
+        assert_eq!(kafka.produce("foo", &[0]).unwrap().offset, 0);
+        assert_eq!(kafka.produce("foo", &large_message).unwrap().offset, 1);
+        kafka.close();
+
+        // Reopening continues appending to the same segment
+        let kafka = Kafka::new(path).unwrap();
+        kafka.open().unwrap();
+        assert_eq!(kafka.produce("foo", &[2]).unwrap().offset, 2);
+        assert_eq!(kafka.partition("foo", 0).unwrap().read().num_segments(), 1);
+        assert!(!path.join("foo/partition_0/segment_000000002").exists());
+
+        assert_eq!(consume_payload(&kafka, "foo"), Some(vec![0]));
+        assert_eq!(consume_payload(&kafka, "foo"), Some(large_message));
+        assert_eq!(consume_payload(&kafka, "foo"), Some(vec![2]));
+        assert_eq!(consume_payload(&kafka, "foo"), None);
+
+        kafka.seek("foo", 0, 1).unwrap();
+        assert_eq!(kafka.consume("foo", 0).unwrap().unwrap().offset, 1);
+        assert_eq!(kafka.consume("foo", 0).unwrap().unwrap().offset, 2);
+    }
+
+    #[test]
+    fn test_recover_torn_write () {
+        let path = Path::new("./test_data/test_recover_torn_write");
+        let kafka = init_kafka_for_test(path);
+
+        kafka.produce("foo", &[0]).unwrap();
+        kafka.produce("foo", &[1]).unwrap();
+        kafka.close();
+
+        // Simulate a crash part way through rewriting the tail block
+        let segment_path = path.join("foo/partition_0/segment_000000000");
+        let mut segment_bytes = fs::read(&segment_path).unwrap();
+        let valid_len = segment_bytes.iter().rposition(|x| *x != 0).unwrap() + 1;
+        segment_bytes[valid_len - 1] ^= 0xff;
+        fs::write(&segment_path, &segment_bytes).unwrap();
+
+        let kafka = Kafka::new(path).unwrap();
+        kafka.open().unwrap();
+
+        let reports = kafka.recovery_reports();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].valid_messages, 1);
+        assert!(reports[0].corrupted);
+        assert_eq!(reports[0].truncated_bytes, SEGMENT_HEADER_BYTES + BUFFER_SIZE as u64 - reports[0].truncated_at);
+
+        assert_eq!(kafka.produce("foo", &[2]).unwrap().offset, 1);
+        assert_eq!(consume_payload(&kafka, "foo"), Some(vec![0]));
+        assert_eq!(consume_payload(&kafka, "foo"), Some(vec![2]));
+        assert_eq!(consume_payload(&kafka, "foo"), None);
+    }
+
+    #[test]
+    fn test_open_rejects_invalid_segment_header () {
+        let path = Path::new("./test_data/test_open_rejects_invalid_segment_header");
+        let kafka = init_kafka_for_test(path);
+
+        kafka.produce("foo", &[0]).unwrap();
+        kafka.close();
+
+        let segment_path = path.join("foo/partition_0/segment_000000000");
+        let mut segment_bytes = fs::read(&segment_path).unwrap();
+        segment_bytes[0..4].copy_from_slice(b"junk");
+        fs::write(&segment_path, &segment_bytes).unwrap();
+
+        let kafka = Kafka::new(path).unwrap();
+        match kafka.open() {
+            Err(Error::InvalidSegmentHeader(segment)) => assert_eq!(segment, segment_path),
+            _ => panic!("Expected invalid segment header"),
+        }
+    }
+
+    #[test]
+    fn test_open_v1_segment () {
+        let path = Path::new("./test_data/test_open_v1_segment");
+        let kafka = init_kafka_for_test(path);
+        kafka.produce("foo", &[0]).unwrap();
+        kafka.close().unwrap();
+
+        // Replace the segment with one written before the attributes byte was added
+        let v1_path = path.join("foo/partition_0/segment_000000000");
+        fs::remove_file(index::index_path(&v1_path)).unwrap();
+        fs::remove_file(index::time_index_path(&v1_path)).unwrap();
+        let records = [Record::new("a0").with_key("a"), Record::new("b0").with_key("b")];
+        segment::write_v1_segment(&v1_path, 0, BUFFER_SIZE, &records.iter().collect::<Vec<_>>()).unwrap();
+
+        let kafka = Kafka::new(path).unwrap();
+        kafka.open().unwrap();
+        assert_eq!(consume_payload(&kafka, "foo"), Some(b"a0".to_vec()));
+
+        // New messages go to a segment of the current version rather than the old one
+        assert_eq!(kafka.produce("foo", b"c0").unwrap().offset, 2);
+        assert_eq!(SegmentHeader::read_path(&v1_path).unwrap().version, 1);
+        assert_eq!(SegmentHeader::read_path(&path.join("foo/partition_0/segment_000000002")).unwrap().version, SEGMENT_VERSION);
+        kafka.close().unwrap();
+
+        let kafka = Kafka::new(path).unwrap();
+        kafka.open().unwrap();
+        let payloads: Vec<Vec<u8>> = (0..3).map(|_| consume_payload(&kafka, "foo").unwrap()).collect();
+        assert_eq!(payloads, vec![b"a0".to_vec(), b"b0".to_vec(), b"c0".to_vec()]);
+        assert_eq!(consume_payload(&kafka, "foo"), None);
+    }
+
+    #[test]
+    fn test_change_block_size () {
+        let path = Path::new("./test_data/test_change_block_size");
+        let kafka = init_kafka_for_test(path);
+
+        kafka.produce("foo", &[0; 100]).unwrap();
+        kafka.close();
+
+        // Existing segments keep their block size, new ones use the configured one
+        let config = TopicConfig { block_size: 64, segment_messages: Some(2), ..TopicConfig::default() };
+        let kafka = Kafka::with_config(path, config).unwrap();
+        kafka.open().unwrap();
+        for i in 1..4 {
+            kafka.produce("foo", &[i; 100]).unwrap();
+        }
+        assert_eq!(kafka.partition("foo", 0).unwrap().read().num_segments(), 2);
+
+        for i in 0..4 {
+            assert_eq!(consume_payload(&kafka, "foo"), Some(vec![i; 100]));
+        }
+        assert_eq!(consume_payload(&kafka, "foo"), None);
+
+        let config = TopicConfig { block_size: 4, ..TopicConfig::default() };
+        let kafka = Kafka::with_config(path, config).unwrap();
+        match kafka.open() {
+            Err(Error::InvalidBlockSize(4)) => (),
+            _ => panic!("Expected invalid block size"),
+        }
+    }
+
+    #[test]
+    fn test_segment_rollover_by_messages () {
+        let path = Path::new("./test_data/test_segment_rollover_by_messages");
+        let config = TopicConfig { segment_messages: Some(2), ..TopicConfig::default() };
+        let kafka = init_kafka_with_config_for_test(path, config);
+
+        for i in 0..5 {
+            assert_eq!(kafka.produce("foo", &[i]).unwrap().offset, i as u64);
+        }
+
+        assert_eq!(kafka.partition("foo", 0).unwrap().read().num_segments(), 3);
+        assert!(path.join("foo/partition_0/segment_000000000").is_file());
+        assert!(path.join("foo/partition_0/segment_000000002").is_file());
+        assert!(path.join("foo/partition_0/segment_000000004").is_file());
+
+        for i in 0..5 {
+            assert_eq!(consume_payload(&kafka, "foo"), Some(vec![i]));
+        }
+        assert_eq!(consume_payload(&kafka, "foo"), None);
+
+        kafka.seek("foo", 0, 3).unwrap();
+        assert_eq!(consume_payload(&kafka, "foo"), Some(vec![3]));
+    }
+
+    #[test]
+    fn test_segment_rollover_by_bytes () {
+        let path = Path::new("./test_data/test_segment_rollover_by_bytes");
+        let config = TopicConfig { segment_bytes: Some(BUFFER_SIZE as u64 * 2), ..TopicConfig::default() };
+        let kafka = init_kafka_with_config_for_test(path, config);
+
+        let message = vec![42; BUFFER_SIZE / 2];
+        for _ in 0..6 {
+            kafka.produce("foo", &message).unwrap();
+        }
+
+        // Each message spills into a second block, so only two fit in a segment
+        assert_eq!(kafka.partition("foo", 0).unwrap().read().num_segments(), 3);
+        assert!(path.join("foo/partition_0/segment_000000002").is_file());
+        assert!(path.join("foo/partition_0/segment_000000004").is_file());
+    }
+
+    #[test]
+    fn test_segment_rollover_counts_keys () {
+        let path = Path::new("./test_data/test_segment_rollover_counts_keys");
+        let segment_bytes = BUFFER_SIZE as u64 * 8;
+        let config = TopicConfig { segment_bytes: Some(segment_bytes), ..TopicConfig::default() };
+        let kafka = init_kafka_with_config_for_test(path, config);
+
+        // The keys take up most of each message, so only one fits in a segment
+        let record = Record::new(vec![1]).with_key(vec![42; BUFFER_SIZE * 4]);
+        for _ in 0..3 {
+            kafka.produce_record("foo", &record).unwrap();
+        }
+
+        assert_eq!(kafka.partition("foo", 0).unwrap().read().num_segments(), 3);
+        for entry in fs::read_dir(path.join("foo/partition_0")).unwrap() {
+            assert!(entry.unwrap().metadata().unwrap().len() <= segment_bytes);
+        }
+    }
+
+    #[test]
+    fn test_segment_rollover_by_age () {
+        let path = Path::new("./test_data/test_segment_rollover_by_age");
+        let config = TopicConfig { segment_age: Some(Duration::from_millis(0)), ..TopicConfig::default() };
+        let kafka = init_kafka_with_config_for_test(path, config);
+
+        for i in 0..3 {
+            kafka.produce("foo", &[i]).unwrap();
+        }
+
+        assert_eq!(kafka.partition("foo", 0).unwrap().read().num_segments(), 3);
+    }
+
+    #[test]
+    fn test_produce_batch () {
+        let path = Path::new("./test_data/test_produce_batch");
+        let config = TopicConfig { sync_policy: SyncPolicy::EveryMessages(2), ..TopicConfig::default() };
+        let kafka = init_kafka_with_config_for_test(path, config);
+
+        kafka.produce("foo", b"first").unwrap();
+        let large_message = vec![7; BUFFER_SIZE * 2];
+        let offsets: Vec<Offset> = kafka.produce_batch("foo", &[b"a", &large_message, b"b"]).unwrap().iter().map(|m| m.offset).collect();
+        assert_eq!(offsets, vec![1, 2, 3]);
+        assert!(kafka.produce_batch("foo", &[]).unwrap().is_empty());
+
+        // One sync covers the whole batch
+        assert_eq!(kafka.partition("foo", 0).unwrap().read().durable_offset(), 4);
+
+        kafka.produce("foo", b"last").unwrap();
+
+        assert_eq!(consume_payload(&kafka, "foo").unwrap(), b"first");
+        assert_eq!(consume_payload(&kafka, "foo").unwrap(), b"a");
+        assert_eq!(consume_payload(&kafka, "foo").unwrap(), large_message);
+        assert_eq!(consume_payload(&kafka, "foo").unwrap(), b"b");
+        assert_eq!(consume_payload(&kafka, "foo").unwrap(), b"last");
+        assert_eq!(consume_payload(&kafka, "foo"), None);
+    }
+
+    #[test]
+    fn test_sync_every_messages () {
+        let path = Path::new("./test_data/test_sync_every_messages");
+        let config = TopicConfig { sync_policy: SyncPolicy::EveryMessages(3), ..TopicConfig::default() };
+        let kafka = init_kafka_with_config_for_test(path, config);
+
+        kafka.produce("foo", b"a").unwrap();
+        kafka.produce("foo", b"b").unwrap();
+        assert_eq!(kafka.partition("foo", 0).unwrap().read().durable_offset(), 0);
+
+        kafka.produce("foo", b"c").unwrap();
+        assert_eq!(kafka.partition("foo", 0).unwrap().read().durable_offset(), 3);
+    }
+
+    #[test]
+    fn test_sync_never_with_durable_acks () {
+        let path = Path::new("./test_data/test_sync_never_with_durable_acks");
+        let config = TopicConfig { sync_policy: SyncPolicy::Never, ..TopicConfig::default() };
+        let kafka = init_kafka_with_config_for_test(path, config);
+
+        kafka.produce("foo", b"a").unwrap();
+        assert_eq!(kafka.partition("foo", 0).unwrap().read().durable_offset(), 0);
+
+        let offset = kafka.producer("foo").unwrap().with_acks(Acks::Durable).send(b"b").unwrap().offset;
+        assert_eq!(offset, 1);
+        assert_eq!(kafka.partition("foo", 0).unwrap().read().durable_offset(), 2);
+
+        kafka.produce("foo", b"c").unwrap();
+        kafka.producer("foo").unwrap().flush().unwrap();
+        assert_eq!(kafka.partition("foo", 0).unwrap().read().durable_offset(), 3);
+    }
+
+    #[test]
+    fn test_sync_interval () {
+        let path = Path::new("./test_data/test_sync_interval");
+        let config = TopicConfig { sync_policy: SyncPolicy::Interval(Duration::from_millis(0)), ..TopicConfig::default() };
+        let kafka = init_kafka_with_config_for_test(path, config);
+
+        kafka.produce("foo", b"a").unwrap();
+        assert_eq!(kafka.partition("foo", 0).unwrap().read().durable_offset(), 1);
+    }
+
+    #[test]
+    fn test_sync_interval_without_produces () {
+        let path = Path::new("./test_data/test_sync_interval_without_produces");
+        let config = TopicConfig { sync_policy: SyncPolicy::Interval(Duration::from_millis(50)), ..TopicConfig::default() };
+        let kafka = init_kafka_with_config_for_test(path, config);
+
+        kafka.produce("foo", b"a").unwrap();
+        kafka.produce("foo", b"b").unwrap();
+
+        // The flusher syncs the last messages though nothing is produced after them
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while kafka.partition("foo", 0).unwrap().read().durable_offset() < 2 {
+            assert!(Instant::now() < deadline, "Messages never synced");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_durable_produces_share_syncs () {
+        let path = Path::new("./test_data/test_durable_produces_share_syncs");
+        let kafka = init_kafka_for_test(path);
+        kafka.create_topic("foo", 1).unwrap();
+
+        let producers: Vec<_> = (0..8).map(|_| {
+            let kafka = kafka.clone();
+            thread::spawn(move || {
+                let mut producer = kafka.producer("foo").unwrap().with_acks(Acks::Durable);
+                for i in 0..25u8 {
+                    producer.send(&[i]).unwrap();
+                }
+            })
+        }).collect();
+
+        for producer in producers {
+            producer.join().unwrap();
+        }
+
+        // Producers waiting on a sync in progress are covered by the one after it
+        let partition = kafka.partition("foo", 0).unwrap();
+        assert_eq!(partition.read().durable_offset(), 200);
+        assert!(partition.read().num_syncs() < 200, "{} syncs for 200 produces", partition.read().num_syncs());
     }
 
     #[test]
     #[ignore]
+    #[allow(clippy::needless_range_loop, clippy::needless_borrow, redundant_semicolons)]
     fn test_produce_throughput_perf () {
         let path = Path::new("./test_data/test_produce_throughput_perf");
-        let mut kafka = init_kafka_for_test(&path);
+        let kafka = init_kafka_for_test(&path);
 
         let start_time = SystemTime::now();
 
@@ -150,9 +1528,10 @@ mod tests {
 
     #[test]
     #[ignore]
+    #[allow(clippy::needless_range_loop, clippy::needless_borrow)]
     fn test_produce_size_perf () {
         let path = Path::new("./test_data/test_produce_size_perf");
-        let mut kafka = init_kafka_for_test(&path);
+        let kafka = init_kafka_for_test(&path);
 
         let test_num_produces = 40000;
         let test_message_size = 256;
@@ -180,15 +1559,22 @@ mod tests {
     }
 
     fn init_kafka_for_test(path: &Path) -> Kafka {
-        fs::remove_dir_all(path);
+        init_kafka_with_config_for_test(path, TopicConfig::default())
+    }
 
-        let path = Path::new(path);
+    fn init_kafka_with_config_for_test(path: &Path, config: TopicConfig) -> Kafka {
+        fs::remove_dir_all(path);
 
-        let mut kafka = Kafka::new(&path).unwrap();
+        let kafka = Kafka::with_config(path, config).unwrap();
         assert!(kafka.open().is_ok());
         kafka
     }
 
+    fn consume_payload(kafka: &Kafka, topic_name: &str) -> Option<Vec<u8>> {
+        kafka.consume(topic_name, 0).unwrap().map(|message| message.payload)
+    }
+
+    #[allow(deprecated)]
     fn calculate_dir_size(dir: &Path) -> io::Result<u64> {
         let mut dir_size = 0;
 
@@ -5,14 +5,90 @@
 
 extern crate crc;
 extern crate rand;
+#[cfg(feature = "async")]
+extern crate futures;
 
+mod assignor;
+#[cfg(feature = "async")]
+mod async_client;
+mod config;
+mod consumer;
+mod coordinator;
+mod error;
+mod group_commit;
+mod index;
+mod notify;
+mod offsets;
+mod partition;
+mod partitioner;
+mod producer;
+mod protocol;
+mod record;
 mod segment;
+mod server;
 mod topic;
 mod kafka;
 
+#[cfg(feature = "async")]
+pub use async_client::{AsyncConsumer, AsyncProducer};
+pub use assignor::{Assignment, AssignmentStrategy, MemberId, RangeStrategy, RoundRobinStrategy, StickyStrategy, TopicPartition};
+pub use config::{Acks, CleanupPolicy, SyncPolicy, TopicConfig};
+pub use consumer::Consumer;
+pub use coordinator::{GroupCoordinator, RebalanceListener};
+pub use error::{Error, Result};
+pub use kafka::Kafka;
+pub use partitioner::{HashPartitioner, Partitioner, RoundRobinPartitioner};
+pub use producer::Producer;
+pub use record::{Header, Record, RecordMetadata};
+pub use segment::{Message, Offset, RecoveryReport};
+pub use server::Server;
+
 #[cfg(test)]
 mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use super::{Consumer, Error, Kafka, Message, Offset, Producer, TopicConfig};
+
     #[test]
     fn it_works() {
     }
+
+    #[test]
+    fn test_public_api() {
+        let path = Path::new("./test_data/test_public_api");
+        fs::remove_dir_all(path);
+
+        let config = TopicConfig { segment_messages: Some(2), ..TopicConfig::default() };
+        let kafka = Kafka::with_config(path, config).unwrap();
+        kafka.open().unwrap();
+
+        {
+            let mut producer: Producer = kafka.producer("events").unwrap();
+            for i in 0..5 {
+                let offset: Offset = producer.send(&[i]).unwrap().offset;
+                assert_eq!(offset, i as Offset);
+            }
+        }
+
+        assert_eq!(kafka.topic_names(), vec!["events"]);
+
+        let mut first: Consumer = kafka.consumer("events", 0).unwrap();
+        let mut second = kafka.consumer("events", 0).unwrap();
+        second.seek(3).unwrap();
+
+        let message: Message = first.poll().unwrap().unwrap();
+        assert_eq!((message.offset, message.payload), (0, vec![0]));
+        assert_eq!(first.position(), 1);
+
+        assert_eq!(second.poll().unwrap().unwrap().payload, vec![3]);
+        assert_eq!(second.poll().unwrap().unwrap().payload, vec![4]);
+        assert!(second.poll().unwrap().is_none());
+        assert_eq!(first.poll().unwrap().unwrap().payload, vec![1]);
+
+        match kafka.consumer("missing", 0) {
+            Err(Error::TopicNotFound(_)) => (),
+            _ => panic!("Expected topic not found"),
+        }
+    }
 }
@@ -0,0 +1,154 @@
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Weak};
+use std::time::Instant;
+
+use segment::Offset;
+
+// Wakes readers waiting at the end of a topic when new messages are appended to it
+pub struct AppendNotifier {
+    // Offset following the last message appended
+    next_offset: Mutex<Offset>,
+    appended: Condvar,
+    // Waiters watching this topic among others, in `wait_for_any`
+    watchers: Mutex<Vec<Weak<Wakeup>>>
+}
+
+// Woken by an append to any of the topics it watches
+#[derive(Default)]
+struct Wakeup {
+    woken: Mutex<bool>,
+    condvar: Condvar
+}
+
+impl AppendNotifier {
+    pub fn new(next_offset: Offset) -> AppendNotifier {
+        AppendNotifier { next_offset: Mutex::new(next_offset), appended: Condvar::new(), watchers: Mutex::new(Vec::new()) }
+    }
+
+    // Records that every offset below `next_offset` has been appended, waking all waiters
+    pub fn appended(&self, next_offset: Offset) {
+        {
+            let mut current = self.lock();
+            *current = (*current).max(next_offset);
+            self.appended.notify_all();
+        }
+
+        self.watchers().retain(|watcher| match watcher.upgrade() {
+            Some(wakeup) => {
+                *lock(&wakeup.woken) = true;
+                wakeup.condvar.notify_all();
+                true
+            },
+            None => false,
+        });
+    }
+
+    fn has_appended(&self, offset: Offset) -> bool {
+        *self.lock() > offset
+    }
+
+    fn watch(&self, wakeup: &Arc<Wakeup>) {
+        let mut watchers = self.watchers();
+        watchers.retain(|watcher| watcher.strong_count() > 0);
+        watchers.push(Arc::downgrade(wakeup));
+    }
+
+    fn watchers(&self) -> MutexGuard<'_, Vec<Weak<Wakeup>>> {
+        lock(&self.watchers)
+    }
+
+    // Blocks until the message at `offset` has been appended, returning false if `deadline` passes first
+    pub fn wait_for(&self, offset: Offset, deadline: Instant) -> bool {
+        let mut next_offset = self.lock();
+
+        loop {
+            if *next_offset > offset {
+                return true;
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+
+            next_offset = self.appended.wait_timeout(next_offset, deadline - now).unwrap_or_else(|e| e.into_inner()).0;
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Offset> {
+        lock(&self.next_offset)
+    }
+}
+
+// Blocks until any of the notifiers has appended the message at the offset paired with it,
+// returning false if `deadline` passes first
+pub fn wait_for_any(watched: &[(Arc<AppendNotifier>, Offset)], deadline: Instant) -> bool {
+    let wakeup = Arc::new(Wakeup::default());
+    for (notifier, _) in watched {
+        notifier.watch(&wakeup);
+    }
+
+    let mut woken = lock(&wakeup.woken);
+    loop {
+        // Checked with `woken` held, so an append made meanwhile is either seen here or wakes the wait
+        if watched.iter().any(|&(ref notifier, offset)| notifier.has_appended(offset)) {
+            return true;
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            return false;
+        }
+
+        if !*woken {
+            woken = wakeup.condvar.wait_timeout(woken, deadline - now).unwrap_or_else(|e| e.into_inner()).0;
+        }
+        *woken = false;
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_wait_for_append() {
+        let notifier = Arc::new(AppendNotifier::new(3));
+        assert!(notifier.wait_for(2, Instant::now()));
+        assert!(!notifier.wait_for(3, Instant::now() + Duration::from_millis(20)));
+
+        let waiter = {
+            let notifier = notifier.clone();
+            thread::spawn(move || notifier.wait_for(4, Instant::now() + Duration::from_secs(10)))
+        };
+
+        notifier.appended(4);
+        thread::sleep(Duration::from_millis(20));
+        notifier.appended(5);
+        assert!(waiter.join().unwrap());
+    }
+
+    #[test]
+    fn test_wait_for_any() {
+        let first = Arc::new(AppendNotifier::new(3));
+        let second = Arc::new(AppendNotifier::new(7));
+        assert!(wait_for_any(&[(first.clone(), 3), (second.clone(), 6)], Instant::now()));
+        assert!(!wait_for_any(&[(first.clone(), 3), (second.clone(), 7)], Instant::now() + Duration::from_millis(20)));
+        assert!(!wait_for_any(&[], Instant::now()));
+
+        let waiter = {
+            let watched = vec![(first.clone(), 3), (second.clone(), 7)];
+            thread::spawn(move || wait_for_any(&watched, Instant::now() + Duration::from_secs(10)))
+        };
+
+        thread::sleep(Duration::from_millis(20));
+        second.appended(8);
+        assert!(waiter.join().unwrap());
+    }
+}
@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+
+use config::{Acks, CleanupPolicy, TopicConfig};
+use error::Result;
+use record::Record;
+use segment::{read_u32, read_u64, write_u32, write_u64, Offset};
+use topic::Topic;
+
+// Internal topic holding the offsets committed by consumer groups
+pub const CONSUMER_OFFSETS_TOPIC: &str = "__consumer_offsets";
+
+// Segments of the offsets topic are kept small, as only sealed segments get compacted, and a
+// commit is only a few dozen bytes
+const OFFSETS_SEGMENT_BYTES: u64 = 1024 * 1024;
+const OFFSETS_SEGMENT_MESSAGES: u64 = 1000;
+
+// Committed offsets, by group and partition. Every commit is appended to the offsets topic as a
+// record keyed by the group and partition, so compaction keeps just the latest commit of each, and
+// the map is rebuilt by replaying the topic on open.
+pub struct OffsetStore {
+    state: Mutex<OffsetState>
+}
+
+struct OffsetState {
+    topic: Topic,
+    committed: HashMap<(String, String, u32), Offset>
+}
+
+impl OffsetStore {
+    pub fn open(dir: &Path) -> Result<OffsetStore> {
+        let config = TopicConfig {
+            segment_bytes: Some(OFFSETS_SEGMENT_BYTES),
+            segment_messages: Some(OFFSETS_SEGMENT_MESSAGES),
+            ..TopicConfig::default()
+        };
+        OffsetStore::with_config(dir, config)
+    }
+
+    // The offsets topic is always compacted, whatever the cleanup policy of `config`
+    pub fn with_config(dir: &Path, config: TopicConfig) -> Result<OffsetStore> {
+        let config = TopicConfig { cleanup_policy: CleanupPolicy::Compact, ..config };
+        let topic = Topic::new(&dir.join(CONSUMER_OFFSETS_TOPIC), config)?;
+
+        let mut committed = HashMap::new();
+        let mut cursor = topic.cursor_at(topic.log_start_offset())?;
+        while let Some(message) = topic.read(&mut cursor)? {
+            let key = match message.key.as_ref().and_then(|key| decode_key(key)) {
+                Some(key) => key,
+                None => continue,
+            };
+
+            if message.tombstone {
+                committed.remove(&key);
+            } else {
+                committed.insert(key, read_u64(&message.payload, 0)?);
+            }
+        }
+
+        Ok(OffsetStore { state: Mutex::new(OffsetState { topic, committed }) })
+    }
+
+    // Records that `group` has consumed the partition up to, but not including, `offset`. Returns
+    // once the commit is on disk.
+    pub fn commit(&self, group: &str, topic: &str, partition: u32, offset: Offset) -> Result<()> {
+        let mut payload = vec![0; 8];
+        write_u64(&mut payload, offset, 0)?;
+        let record = Record::new(payload).with_key(encode_key(group, topic, partition)?);
+
+        let mut state = self.lock();
+        state.topic.produce(&record, Acks::Durable)?;
+        state.committed.insert((group.to_string(), topic.to_string(), partition), offset);
+        Ok(())
+    }
+
+    pub fn committed(&self, group: &str, topic: &str, partition: u32) -> Option<Offset> {
+        self.lock().committed.get(&(group.to_string(), topic.to_string(), partition)).cloned()
+    }
+
+    pub fn compact(&self) -> Result<u64> {
+        self.lock().topic.compact()
+    }
+
+    pub fn close(&self) -> Result<()> {
+        self.lock().topic.close()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, OffsetState> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+// Group length(4), group, partition(4), then topic
+fn encode_key(group: &str, topic: &str, partition: u32) -> Result<Vec<u8>> {
+    let topic_start = 8 + group.len();
+    let mut key = vec![0; topic_start + topic.len()];
+    write_u32(&mut key, group.len() as u32, 0)?;
+    key[4..(4 + group.len())].copy_from_slice(group.as_bytes());
+    write_u32(&mut key, partition, 4 + group.len())?;
+    key[topic_start..].copy_from_slice(topic.as_bytes());
+    Ok(key)
+}
+
+fn decode_key(key: &[u8]) -> Option<(String, String, u32)> {
+    let group_len = read_u32(key, 0).ok()? as usize;
+    let topic_start = 8 + group_len;
+    if topic_start > key.len() {
+        return None;
+    }
+
+    let group = String::from_utf8(key[4..(4 + group_len)].to_vec()).ok()?;
+    let partition = read_u32(key, 4 + group_len).ok()?;
+    let topic = String::from_utf8(key[topic_start..].to_vec()).ok()?;
+    Some((group, topic, partition))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_compact_and_reopen() {
+        let dir = Path::new("./test_data/offsets/test_compact_and_reopen");
+        fs::remove_dir_all(dir);
+
+        let config = TopicConfig { segment_messages: Some(2), ..TopicConfig::default() };
+        let store = OffsetStore::with_config(dir, config.clone()).unwrap();
+        store.commit("workers", "foo", 0, 2).unwrap();
+        store.commit("workers", "foo", 0, 3).unwrap();
+        store.commit("others", "foo", 0, 1).unwrap();
+        store.commit("workers", "foo", 1, 7).unwrap();
+        store.commit("workers", "foo", 0, 4).unwrap();
+
+        // Compaction only sees the sealed segments, where just the first commit is superseded
+        assert_eq!(store.compact().unwrap(), 1);
+        store.close().unwrap();
+
+        let store = OffsetStore::with_config(dir, config).unwrap();
+        assert_eq!(store.committed("workers", "foo", 0), Some(4));
+        assert_eq!(store.committed("others", "foo", 0), Some(1));
+        assert_eq!(store.committed("workers", "foo", 1), Some(7));
+        assert_eq!(store.committed("others", "foo", 1), None);
+    }
+
+    #[test]
+    fn test_default_config_compacts() {
+        let dir = Path::new("./test_data/offsets/test_default_config_compacts");
+        fs::remove_dir_all(dir);
+
+        let store = OffsetStore::open(dir).unwrap();
+        for offset in 0..(OFFSETS_SEGMENT_MESSAGES + 1) {
+            store.commit("workers", "foo", 0, offset).unwrap();
+        }
+
+        // The first segment is sealed, leaving all but its last commit superseded
+        assert_eq!(store.compact().unwrap(), OFFSETS_SEGMENT_MESSAGES - 1);
+        store.close().unwrap();
+
+        let store = OffsetStore::open(dir).unwrap();
+        assert_eq!(store.committed("workers", "foo", 0), Some(OFFSETS_SEGMENT_MESSAGES));
+    }
+
+    #[test]
+    fn test_key_round_trip() {
+        let key = encode_key("group", "topic", 3).unwrap();
+        assert_eq!(decode_key(&key), Some(("group".to_string(), "topic".to_string(), 3)));
+        assert_eq!(decode_key(&key[..10]), None);
+    }
+}
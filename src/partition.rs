@@ -0,0 +1,78 @@
+use std::ops::Range;
+use std::sync::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use config::Acks;
+use error::Result;
+use record::Record;
+use segment::{Message, Offset};
+use topic::{self, Cursor, Topic};
+
+// One partition of a topic, shared by every handle, producer and consumer using it. Appends take
+// the write lock, so partitions are written independently of each other, while reads share the
+// read lock. Consumers only take it to find the segment they read, which they read up to the
+// position its last append committed.
+pub struct Partition {
+    log: RwLock<Topic>,
+    // Where `Kafka::consume` reads from, shared by every handle
+    cursor: Mutex<Cursor>,
+    // Held for the whole of a compaction, so only one at a time writes the cleaned segments
+    compaction: Mutex<()>
+}
+
+impl Partition {
+    pub fn new(log: Topic) -> Partition {
+        Partition { log: RwLock::new(log), cursor: Mutex::new(Cursor::default()), compaction: Mutex::new(()) }
+    }
+
+    pub fn read(&self) -> RwLockReadGuard<'_, Topic> {
+        self.log.read().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn write(&self) -> RwLockWriteGuard<'_, Topic> {
+        self.log.write().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // Positions the shared cursor so the next consume returns the message at `offset`
+    pub fn seek(&self, offset: Offset) -> Result<()> {
+        let mut cursor = self.cursor();
+        *cursor = self.read().cursor_at(offset)?;
+        Ok(())
+    }
+
+    // Reads the next message from the shared cursor. As with a `Consumer`, the partition is only
+    // locked to find the segment to read, so consuming doesn't hold up producers.
+    pub fn consume(&self) -> Result<Option<Message>> {
+        topic::read(|| self.read(), &mut self.cursor())
+    }
+
+    fn cursor(&self) -> MutexGuard<'_, Cursor> {
+        self.cursor.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // Appends the records, only holding the write lock for the append itself. Waiting on the sync
+    // without it lets readers carry on and other producers append, sharing the sync.
+    pub fn produce_batch(&self, records: &[&Record], acks: Acks) -> Result<Range<Offset>> {
+        let (offsets, sync) = self.write().append_batch(records, acks)?;
+        if let Some(sync) = sync {
+            sync.wait()?;
+        }
+
+        Ok(offsets)
+    }
+
+    // Syncs everything appended so far, without holding the lock during the sync
+    pub fn sync(&self) -> Result<()> {
+        let sync = self.write().pending_sync()?;
+        sync.wait()
+    }
+
+    // Compacts the partition, only taking the write lock to swap in the cleaned segments, so
+    // producers and consumers carry on while they are written
+    pub fn compact(&self) -> Result<u64> {
+        let _compacting = self.compaction.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        let compaction = self.read().start_compaction()?;
+        let cleaned = compaction.clean()?;
+        self.write().finish_compaction(cleaned)
+    }
+}
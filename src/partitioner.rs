@@ -0,0 +1,112 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use record::Record;
+
+// Picks the partition, out of `num_partitions`, that a record is appended to. Records sent to the
+// same partition keep their order. A record with its `partition` set goes there instead.
+pub trait Partitioner: Send + Sync {
+    fn partition(&self, topic: &str, record: &Record, num_partitions: u32) -> u32;
+}
+
+// Sends records with the same key to the same partition, hashing the key the way Kafka's Java
+// client does. Records without a key are spread over the partitions in turn.
+#[derive(Default)]
+pub struct HashPartitioner {
+    keyless: RoundRobinPartitioner
+}
+
+impl HashPartitioner {
+    pub fn new() -> HashPartitioner {
+        HashPartitioner::default()
+    }
+}
+
+impl Partitioner for HashPartitioner {
+    fn partition(&self, topic: &str, record: &Record, num_partitions: u32) -> u32 {
+        match record.key {
+            Some(ref key) => (murmur2(key) & 0x7fff_ffff) % num_partitions,
+            None => self.keyless.partition(topic, record, num_partitions),
+        }
+    }
+}
+
+// Spreads records over the partitions in turn, whatever their key
+#[derive(Default)]
+pub struct RoundRobinPartitioner {
+    next: AtomicUsize
+}
+
+impl RoundRobinPartitioner {
+    pub fn new() -> RoundRobinPartitioner {
+        RoundRobinPartitioner::default()
+    }
+}
+
+impl Partitioner for RoundRobinPartitioner {
+    fn partition(&self, topic: &str, record: &Record, num_partitions: u32) -> u32 {
+        (self.next.fetch_add(1, Ordering::Relaxed) % num_partitions as usize) as u32
+    }
+}
+
+// The 32 bit MurmurHash2 used by Kafka to partition keys
+pub fn murmur2(data: &[u8]) -> u32 {
+    const SEED: u32 = 0x9747_b28c;
+    const M: u32 = 0x5bd1_e995;
+    const R: u32 = 24;
+
+    let mut h = SEED ^ data.len() as u32;
+
+    let mut words = data.chunks_exact(4);
+    for word in &mut words {
+        let mut k = u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        h = h.wrapping_mul(M);
+        h ^= k;
+    }
+
+    let rest = words.remainder();
+    if !rest.is_empty() {
+        for (i, &byte) in rest.iter().enumerate() {
+            h ^= (byte as u32) << (8 * i);
+        }
+        h = h.wrapping_mul(M);
+    }
+
+    h ^= h >> 13;
+    h = h.wrapping_mul(M);
+    h ^= h >> 15;
+    h
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_murmur2_matches_kafka() {
+        assert_eq!(murmur2(b"21") as i32, -973932308);
+        assert_eq!(murmur2(b"foobar") as i32, -790332482);
+        assert_eq!(murmur2(b"a-little-bit-long-string") as i32, -985981536);
+        assert_eq!(murmur2(b"a-little-bit-longer-string") as i32, -1486304829);
+        assert_eq!(murmur2(b"abc") as i32, 479470107);
+    }
+
+    #[test]
+    fn test_partitioners() {
+        let partitioner = HashPartitioner::new();
+        let keyed = Record::new("x").with_key("user-1");
+        let partition = partitioner.partition("foo", &keyed, 8);
+        for _ in 0..3 {
+            assert_eq!(partitioner.partition("foo", &keyed, 8), partition);
+        }
+
+        let keyless: Vec<u32> = (0..4).map(|_| partitioner.partition("foo", &Record::new("x"), 3)).collect();
+        assert_eq!(keyless, vec![0, 1, 2, 0]);
+
+        let partitioner = RoundRobinPartitioner::new();
+        let partitions: Vec<u32> = (0..4).map(|_| partitioner.partition("foo", &keyed, 2)).collect();
+        assert_eq!(partitions, vec![0, 1, 0, 1]);
+    }
+}
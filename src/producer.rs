@@ -0,0 +1,88 @@
+use std::sync::Arc;
+
+use config::Acks;
+use error::{Error, Result};
+use partition::Partition;
+use partitioner::Partitioner;
+use record::{Record, RecordMetadata};
+
+// Appends messages to the partitions of a single topic
+pub struct Producer {
+    topic_name: String,
+    partitions: Vec<Arc<Partition>>,
+    partitioner: Arc<dyn Partitioner>,
+    acks: Acks
+}
+
+impl Producer {
+    pub(crate) fn new(topic_name: &str, partitions: Vec<Arc<Partition>>, partitioner: Arc<dyn Partitioner>) -> Producer {
+        Producer { topic_name: topic_name.to_string(), partitions, partitioner, acks: Acks::Written }
+    }
+
+    // Sets when `send` returns
+    pub fn with_acks(mut self, acks: Acks) -> Producer {
+        self.acks = acks;
+        self
+    }
+
+    // Appends the message, returning where it was written
+    pub fn send(&mut self, message: &[u8]) -> Result<RecordMetadata> {
+        self.send_record(&Record::new(message))
+    }
+
+    // Appends the messages with a single write to each partition they go to
+    pub fn send_batch(&mut self, messages: &[&[u8]]) -> Result<Vec<RecordMetadata>> {
+        let records: Vec<Record> = messages.iter().map(|message| Record::new(*message)).collect();
+        self.send_records(&records)
+    }
+
+    pub fn send_record(&mut self, record: &Record) -> Result<RecordMetadata> {
+        let partition = self.partition_for(record)?;
+        let offset = self.partitions[partition as usize].produce_batch(&[record], self.acks)?.start;
+        Ok(RecordMetadata { partition, offset })
+    }
+
+    // Appends the records with a single write to each partition they go to, returning where each
+    // was written, in order
+    pub fn send_records(&mut self, records: &[Record]) -> Result<Vec<RecordMetadata>> {
+        let mut batches: Vec<Vec<&Record>> = vec![Vec::new(); self.partitions.len()];
+        let mut placement = Vec::with_capacity(records.len());
+        for record in records {
+            let partition = self.partition_for(record)?;
+            placement.push((partition, batches[partition as usize].len() as u64));
+            batches[partition as usize].push(record);
+        }
+
+        let mut first_offsets = vec![0; self.partitions.len()];
+        for (partition, batch) in batches.iter().enumerate().filter(|&(_, batch)| !batch.is_empty()) {
+            first_offsets[partition] = self.partitions[partition].produce_batch(batch, self.acks)?.start;
+        }
+
+        let metadata = placement.into_iter()
+            .map(|(partition, i)| RecordMetadata { partition, offset: first_offsets[partition as usize] + i })
+            .collect();
+        Ok(metadata)
+    }
+
+    // Syncs everything sent so far to disk
+    pub fn flush(&mut self) -> Result<()> {
+        for partition in &self.partitions {
+            partition.sync()?;
+        }
+
+        Ok(())
+    }
+
+    fn partition_for(&self, record: &Record) -> Result<u32> {
+        let num_partitions = self.partitions.len() as u32;
+        let partition = match record.partition {
+            Some(partition) => partition,
+            None => self.partitioner.partition(&self.topic_name, record, num_partitions),
+        };
+
+        if partition >= num_partitions {
+            return Err(Error::PartitionNotFound { topic: self.topic_name.clone(), partition });
+        }
+        Ok(partition)
+    }
+}
@@ -0,0 +1,540 @@
+use crc::crc32;
+
+use error::{Error, Result};
+use record::{Header, Record};
+use segment::Message;
+
+// The subset of the Kafka binary protocol the server speaks. Only the non-flexible versions of
+// each request are supported, and records travel in record batches (magic 2), uncompressed.
+
+pub const API_PRODUCE: i16 = 0;
+pub const API_FETCH: i16 = 1;
+pub const API_LIST_OFFSETS: i16 = 2;
+pub const API_METADATA: i16 = 3;
+pub const API_OFFSET_COMMIT: i16 = 8;
+pub const API_OFFSET_FETCH: i16 = 9;
+pub const API_FIND_COORDINATOR: i16 = 10;
+pub const API_VERSIONS: i16 = 18;
+
+// Each request and the range of versions supported for it
+pub const SUPPORTED_APIS: &[(i16, i16, i16)] = &[
+    (API_PRODUCE, 3, 5),
+    (API_FETCH, 4, 4),
+    (API_LIST_OFFSETS, 1, 2),
+    (API_METADATA, 0, 1),
+    (API_OFFSET_COMMIT, 2, 3),
+    (API_OFFSET_FETCH, 1, 3),
+    (API_FIND_COORDINATOR, 0, 0),
+    (API_VERSIONS, 0, 2)
+];
+
+pub const NONE: i16 = 0;
+pub const UNKNOWN_SERVER_ERROR: i16 = -1;
+pub const OFFSET_OUT_OF_RANGE: i16 = 1;
+pub const CORRUPT_MESSAGE: i16 = 2;
+pub const UNKNOWN_TOPIC_OR_PARTITION: i16 = 3;
+pub const INVALID_TOPIC_EXCEPTION: i16 = 17;
+pub const UNSUPPORTED_VERSION: i16 = 35;
+pub const UNSUPPORTED_COMPRESSION_TYPE: i16 = 76;
+
+// Layout of a record batch, after its base offset and length
+const BATCH_MAGIC: i8 = 2;
+const BATCH_CRC_OFFSET: usize = 17;      // 17-20, counted from the start of the batch
+const BATCH_ATTRIBUTES_OFFSET: usize = 21;
+const BATCH_COMPRESSION_MASK: i16 = 0x07;
+const BATCH_CONTROL_FLAG: i16 = 0x20;
+// From the partition leader epoch through the record count, which the batch length covers
+const BATCH_HEADER_BYTES: i32 = 49;
+
+// The error code a client gets for a failed request
+pub fn error_code(error: &Error) -> i16 {
+    match *error {
+        Error::TopicNotFound(_) | Error::PartitionNotFound { .. } => UNKNOWN_TOPIC_OR_PARTITION,
+        Error::OffsetOutOfRange(_) => OFFSET_OUT_OF_RANGE,
+        Error::Corruption { .. } | Error::MalformedRequest | Error::EmptyMessage => CORRUPT_MESSAGE,
+        Error::UnsupportedCompression => UNSUPPORTED_COMPRESSION_TYPE,
+        Error::ReservedTopicName(_) => INVALID_TOPIC_EXCEPTION,
+        _ => UNKNOWN_SERVER_ERROR,
+    }
+}
+
+// What precedes the body of every request
+#[derive(Debug, PartialEq)]
+pub struct RequestHeader {
+    pub api_key: i16,
+    pub api_version: i16,
+    pub correlation_id: i32,
+    pub client_id: Option<String>
+}
+
+impl RequestHeader {
+    pub fn read(decoder: &mut Decoder) -> Result<RequestHeader> {
+        Ok(RequestHeader {
+            api_key: decoder.read_i16()?,
+            api_version: decoder.read_i16()?,
+            correlation_id: decoder.read_i32()?,
+            client_id: decoder.read_nullable_string()?
+        })
+    }
+
+    pub fn write(&self, encoder: &mut Encoder) {
+        encoder.write_i16(self.api_key);
+        encoder.write_i16(self.api_version);
+        encoder.write_i32(self.correlation_id);
+        encoder.write_nullable_string(self.client_id.as_deref());
+    }
+}
+
+// Reads big-endian protocol fields from the front of a buffer
+pub struct Decoder<'a> {
+    buffer: &'a [u8],
+    position: usize
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(buffer: &'a [u8]) -> Decoder<'a> {
+        Decoder { buffer, position: 0 }
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.buffer.len() - self.position
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool> {
+        Ok(self.read_i8()? != 0)
+    }
+
+    pub fn read_i8(&mut self) -> Result<i8> {
+        Ok(self.take(1)?[0] as i8)
+    }
+
+    pub fn read_i16(&mut self) -> Result<i16> {
+        let bytes = self.take(2)?;
+        Ok(i16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn read_i32(&mut self) -> Result<i32> {
+        let bytes = self.take(4)?;
+        Ok(i32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn read_i64(&mut self) -> Result<i64> {
+        let bytes = self.take(8)?;
+        let mut x = [0; 8];
+        x.copy_from_slice(bytes);
+        Ok(i64::from_be_bytes(x))
+    }
+
+    // Zigzag encoded, seven bits to a byte
+    pub fn read_varlong(&mut self) -> Result<i64> {
+        let mut x: u64 = 0;
+        let mut shift = 0;
+        loop {
+            if shift >= 64 {
+                return Err(Error::MalformedRequest);
+            }
+
+            let byte = self.take(1)?[0];
+            x |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+            shift += 7;
+        }
+
+        Ok((x >> 1) as i64 ^ -((x & 1) as i64))
+    }
+
+    pub fn read_varint(&mut self) -> Result<i32> {
+        let x = self.read_varlong()?;
+        if x < i32::MIN as i64 || x > i32::MAX as i64 {
+            return Err(Error::MalformedRequest);
+        }
+        Ok(x as i32)
+    }
+
+    pub fn read_string(&mut self) -> Result<String> {
+        self.read_nullable_string()?.ok_or(Error::MalformedRequest)
+    }
+
+    pub fn read_nullable_string(&mut self) -> Result<Option<String>> {
+        let len = self.read_i16()?;
+        if len < 0 {
+            return Ok(None);
+        }
+
+        let bytes = self.take(len as usize)?;
+        String::from_utf8(bytes.to_vec()).map(Some).map_err(|_| Error::MalformedRequest)
+    }
+
+    pub fn read_nullable_bytes(&mut self) -> Result<Option<&'a [u8]>> {
+        let len = self.read_i32()?;
+        if len < 0 {
+            return Ok(None);
+        }
+
+        self.take(len as usize).map(Some)
+    }
+
+    // Length of an array, which is -1 for a null one
+    pub fn read_array_len(&mut self) -> Result<Option<usize>> {
+        let len = self.read_i32()?;
+        if len < 0 {
+            return Ok(None);
+        }
+
+        // Every element takes at least a byte
+        if len as usize > self.remaining() {
+            return Err(Error::MalformedRequest);
+        }
+        Ok(Some(len as usize))
+    }
+
+    // Reads an array with `read_element`, treating a null array as empty
+    pub fn read_array<T, F>(&mut self, mut read_element: F) -> Result<Vec<T>> where F: FnMut(&mut Decoder<'a>) -> Result<T> {
+        let len = self.read_array_len()?.unwrap_or(0);
+        let mut elements = Vec::with_capacity(len);
+        for _ in 0..len {
+            elements.push(read_element(self)?);
+        }
+
+        Ok(elements)
+    }
+
+    // Reads a varint length followed by that many bytes, where -1 means null
+    fn read_varint_bytes(&mut self) -> Result<Option<&'a [u8]>> {
+        let len = self.read_varint()?;
+        if len < 0 {
+            return Ok(None);
+        }
+
+        self.take(len as usize).map(Some)
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if len > self.remaining() {
+            return Err(Error::MalformedRequest);
+        }
+
+        let bytes = &self.buffer[self.position..(self.position + len)];
+        self.position += len;
+        Ok(bytes)
+    }
+}
+
+// Writes big-endian protocol fields
+#[derive(Default)]
+pub struct Encoder {
+    buffer: Vec<u8>
+}
+
+impl Encoder {
+    pub fn new() -> Encoder {
+        Encoder::default()
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buffer
+    }
+
+    pub fn len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    pub fn write_bool(&mut self, x: bool) {
+        self.write_i8(x as i8);
+    }
+
+    pub fn write_i8(&mut self, x: i8) {
+        self.buffer.push(x as u8);
+    }
+
+    pub fn write_i16(&mut self, x: i16) {
+        self.buffer.extend_from_slice(&x.to_be_bytes());
+    }
+
+    pub fn write_i32(&mut self, x: i32) {
+        self.buffer.extend_from_slice(&x.to_be_bytes());
+    }
+
+    pub fn write_i64(&mut self, x: i64) {
+        self.buffer.extend_from_slice(&x.to_be_bytes());
+    }
+
+    pub fn write_varlong(&mut self, x: i64) {
+        let mut zigzag = ((x << 1) ^ (x >> 63)) as u64;
+        while zigzag >= 0x80 {
+            self.buffer.push((zigzag as u8 & 0x7f) | 0x80);
+            zigzag >>= 7;
+        }
+        self.buffer.push(zigzag as u8);
+    }
+
+    pub fn write_varint(&mut self, x: i32) {
+        self.write_varlong(x as i64);
+    }
+
+    pub fn write_string(&mut self, x: &str) {
+        self.write_i16(x.len() as i16);
+        self.buffer.extend_from_slice(x.as_bytes());
+    }
+
+    pub fn write_nullable_string(&mut self, x: Option<&str>) {
+        match x {
+            Some(x) => self.write_string(x),
+            None => self.write_i16(-1),
+        }
+    }
+
+    pub fn write_nullable_bytes(&mut self, x: Option<&[u8]>) {
+        match x {
+            Some(x) => {
+                self.write_i32(x.len() as i32);
+                self.buffer.extend_from_slice(x);
+            },
+            None => self.write_i32(-1),
+        }
+    }
+
+    pub fn write_array_len(&mut self, len: usize) {
+        self.write_i32(len as i32);
+    }
+
+    pub fn write_array<T, F>(&mut self, elements: &[T], mut write_element: F) where F: FnMut(&mut Encoder, &T) {
+        self.write_array_len(elements.len());
+        for element in elements {
+            write_element(self, element);
+        }
+    }
+
+    fn write_varint_bytes(&mut self, x: Option<&[u8]>) {
+        match x {
+            Some(x) => {
+                self.write_varint(x.len() as i32);
+                self.buffer.extend_from_slice(x);
+            },
+            None => self.write_varint(-1),
+        }
+    }
+}
+
+// Decodes the record batches in a produce request, in order. Control batches, which mark the
+// end of a transaction, hold no records and are skipped.
+pub fn decode_record_batches(bytes: &[u8]) -> Result<Vec<Record>> {
+    let mut decoder = Decoder::new(bytes);
+    let mut records = Vec::new();
+
+    while decoder.remaining() > 0 {
+        let batch_start = decoder.position;
+        let _base_offset = decoder.read_i64()?;
+        let batch_len = decoder.read_i32()?;
+        if batch_len < BATCH_HEADER_BYTES || batch_len as usize > decoder.remaining() {
+            return Err(Error::MalformedRequest);
+        }
+        let batch_end = decoder.position + batch_len as usize;
+
+        // Everything past the length is read from within the batch
+        let mut batch = Decoder::new(&bytes[decoder.position..batch_end]);
+        decoder.position = batch_end;
+
+        let _partition_leader_epoch = batch.read_i32()?;
+        if batch.read_i8()? != BATCH_MAGIC {
+            return Err(Error::MalformedRequest);
+        }
+        let crc = batch.read_i32()? as u32;
+        if crc32::checksum_castagnoli(&bytes[(batch_start + BATCH_ATTRIBUTES_OFFSET)..batch_end]) != crc {
+            return Err(Error::MalformedRequest);
+        }
+
+        let attributes = batch.read_i16()?;
+        if attributes & BATCH_COMPRESSION_MASK != 0 {
+            return Err(Error::UnsupportedCompression);
+        }
+        let _last_offset_delta = batch.read_i32()?;
+        let base_timestamp = batch.read_i64()?;
+        let _max_timestamp = batch.read_i64()?;
+        let _producer_id = batch.read_i64()?;
+        let _producer_epoch = batch.read_i16()?;
+        let _base_sequence = batch.read_i32()?;
+        let num_records = batch.read_array_len()?.unwrap_or(0);
+
+        if attributes & BATCH_CONTROL_FLAG != 0 {
+            continue;
+        }
+
+        for _ in 0..num_records {
+            records.push(decode_record(&mut batch, base_timestamp)?);
+        }
+    }
+
+    Ok(records)
+}
+
+fn decode_record(batch: &mut Decoder, base_timestamp: i64) -> Result<Record> {
+    let len = batch.read_varint()?;
+    if len < 0 {
+        return Err(Error::MalformedRequest);
+    }
+    let mut decoder = Decoder::new(batch.take(len as usize)?);
+
+    let _attributes = decoder.read_i8()?;
+    let timestamp = base_timestamp.checked_add(decoder.read_varlong()?).ok_or(Error::MalformedRequest)?;
+    let _offset_delta = decoder.read_varint()?;
+    let key = decoder.read_varint_bytes()?;
+    let value = decoder.read_varint_bytes()?;
+
+    // Every header takes at least two bytes
+    let num_headers = decoder.read_varint()?;
+    if num_headers < 0 || num_headers as usize > decoder.remaining() {
+        return Err(Error::MalformedRequest);
+    }
+    let mut headers = Vec::with_capacity(num_headers as usize);
+    for _ in 0..num_headers {
+        let key = decoder.read_varint_bytes()?.ok_or(Error::MalformedRequest)?;
+        let key = String::from_utf8(key.to_vec()).map_err(|_| Error::MalformedRequest)?;
+        let value = decoder.read_varint_bytes()?.unwrap_or(&[]);
+        headers.push(Header { key, value: value.to_vec() });
+    }
+
+    // A null value deletes the key
+    let mut record = match (key, value) {
+        (Some(key), None) => Record::tombstone(key),
+        (key, value) => Record { key: key.map(|key| key.to_vec()), payload: value.unwrap_or(&[]).to_vec(), ..Record::default() },
+    };
+    record.timestamp = if timestamp >= 0 { Some(timestamp as u64) } else { None };
+    record.headers = headers;
+    Ok(record)
+}
+
+// Encodes the messages, which must be in offset order, as a single record batch
+pub fn encode_record_batch(messages: &[Message]) -> Vec<u8> {
+    let (first, last) = match (messages.first(), messages.last()) {
+        (Some(first), Some(last)) => (first, last),
+        _ => return Vec::new(),
+    };
+    let base_timestamp = first.timestamp as i64;
+    let max_timestamp = messages.iter().map(|message| message.timestamp).max().unwrap_or(first.timestamp);
+
+    let mut encoder = Encoder::new();
+    encoder.write_i64(first.offset as i64);
+    encoder.write_i32(0); // Length, filled in below
+    encoder.write_i32(0); // Partition leader epoch
+    encoder.write_i8(BATCH_MAGIC);
+    encoder.write_i32(0); // crc, filled in below
+    encoder.write_i16(0); // Attributes
+    encoder.write_i32((last.offset - first.offset) as i32);
+    encoder.write_i64(base_timestamp);
+    encoder.write_i64(max_timestamp as i64);
+    encoder.write_i64(-1); // Producer id
+    encoder.write_i16(-1); // Producer epoch
+    encoder.write_i32(-1); // Base sequence
+    encoder.write_array_len(messages.len());
+
+    for message in messages {
+        let mut record = Encoder::new();
+        record.write_i8(0); // Attributes
+        record.write_varlong(message.timestamp as i64 - base_timestamp);
+        record.write_varint((message.offset - first.offset) as i32);
+        record.write_varint_bytes(message.key.as_deref());
+        record.write_varint_bytes(if message.tombstone { None } else { Some(&message.payload) });
+        record.write_varint(message.headers.len() as i32);
+        for header in &message.headers {
+            record.write_varint_bytes(Some(header.key.as_bytes()));
+            record.write_varint_bytes(Some(&header.value));
+        }
+
+        encoder.write_varint(record.len() as i32);
+        encoder.buffer.extend_from_slice(&record.into_bytes());
+    }
+
+    let mut bytes = encoder.into_bytes();
+    let batch_len = (bytes.len() - 12) as i32;
+    bytes[8..12].copy_from_slice(&batch_len.to_be_bytes());
+    let crc = crc32::checksum_castagnoli(&bytes[BATCH_ATTRIBUTES_OFFSET..]);
+    bytes[BATCH_CRC_OFFSET..BATCH_ATTRIBUTES_OFFSET].copy_from_slice(&crc.to_be_bytes());
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_varlong_round_trip() {
+        let mut encoder = Encoder::new();
+        for x in &[0, 1, -1, 63, -64, 64, 300, i64::MAX, i64::MIN] {
+            encoder.write_varlong(*x);
+        }
+
+        let bytes = encoder.into_bytes();
+        assert_eq!(&bytes[..3], &[0, 2, 1]);
+
+        let mut decoder = Decoder::new(&bytes);
+        for x in &[0, 1, -1, 63, -64, 64, 300, i64::MAX, i64::MIN] {
+            assert_eq!(decoder.read_varlong().unwrap(), *x);
+        }
+        assert_eq!(decoder.remaining(), 0);
+    }
+
+    #[test]
+    fn test_record_batch_round_trip() {
+        let messages = vec![
+            Message { offset: 7, position: 0, key: Some(b"k".to_vec()), timestamp: 1000, headers: vec![Header { key: "h".to_string(), value: vec![1] }], tombstone: false, payload: vec![1, 2, 3] },
+            Message { offset: 9, position: 0, key: None, timestamp: 990, headers: Vec::new(), tombstone: false, payload: Vec::new() },
+            Message { offset: 10, position: 0, key: Some(b"k".to_vec()), timestamp: 1005, headers: Vec::new(), tombstone: true, payload: Vec::new() }
+        ];
+
+        let bytes = encode_record_batch(&messages);
+        let records = decode_record_batches(&bytes).unwrap();
+        assert_eq!(records, vec![
+            Record::new(vec![1, 2, 3]).with_key("k").with_timestamp(1000).with_header("h", vec![1]),
+            Record::new(Vec::new()).with_timestamp(990),
+            Record::tombstone("k").with_timestamp(1005)
+        ]);
+
+        let mut corrupted = bytes.clone();
+        let last = corrupted.len() - 1;
+        corrupted[last] ^= 1;
+        match decode_record_batches(&corrupted) {
+            Err(Error::MalformedRequest) => (),
+            _ => panic!("Expected malformed request"),
+        }
+    }
+
+    #[test]
+    fn test_malformed_record_batches() {
+        let message = Message { offset: 0, position: 0, key: None, timestamp: 1000, headers: Vec::new(), tombstone: false, payload: vec![1] };
+        let bytes = encode_record_batch(&[message]);
+
+        // A batch length too short to hold the batch header, and one running past the request
+        let mut short = bytes.clone();
+        for batch_len in &[0, 12, 48, bytes.len() as i32] {
+            short[8..12].copy_from_slice(&batch_len.to_be_bytes());
+            match decode_record_batches(&short) {
+                Err(Error::MalformedRequest) => (),
+                _ => panic!("Expected malformed request for batch length {}", batch_len),
+            }
+        }
+
+        // A record whose timestamp delta overflows the base timestamp
+        let mut record = Encoder::new();
+        record.write_i8(0);
+        record.write_varlong(i64::MAX);
+        record.write_varint(0);
+        record.write_varint_bytes(None);
+        record.write_varint_bytes(Some(&[1]));
+        record.write_varint(0);
+        let mut records = Encoder::new();
+        records.write_varint(record.len() as i32);
+        records.buffer.extend_from_slice(&record.into_bytes());
+        let records = records.into_bytes();
+        match decode_record(&mut Decoder::new(&records), 1000) {
+            Err(Error::MalformedRequest) => (),
+            _ => panic!("Expected malformed request"),
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use segment::Offset;
+
+// A string-keyed value attached to a record, such as a trace id
+#[derive(Clone, Debug, PartialEq)]
+pub struct Header {
+    pub key: String,
+    pub value: Vec<u8>
+}
+
+// A message to produce: its payload plus an optional key, timestamp and headers
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Record {
+    pub key: Option<Vec<u8>>,
+    pub payload: Vec<u8>,
+    // Milliseconds since the epoch. The time of the append is used when not set.
+    pub timestamp: Option<u64>,
+    pub headers: Vec<Header>,
+    // Marks the key as deleted, so compaction drops every earlier record with it
+    pub tombstone: bool,
+    // The partition to append to, overriding the producer's partitioner
+    pub partition: Option<u32>
+}
+
+// Where a produced record ended up
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RecordMetadata {
+    pub partition: u32,
+    pub offset: Offset
+}
+
+impl Record {
+    pub fn new<P: Into<Vec<u8>>>(payload: P) -> Record {
+        Record { payload: payload.into(), ..Record::default() }
+    }
+
+    // A record deleting `key`, which has no payload
+    pub fn tombstone<K: Into<Vec<u8>>>(key: K) -> Record {
+        Record { key: Some(key.into()), tombstone: true, ..Record::default() }
+    }
+
+    pub fn with_key<K: Into<Vec<u8>>>(mut self, key: K) -> Record {
+        self.key = Some(key.into());
+        self
+    }
+
+    pub fn with_timestamp(mut self, timestamp: u64) -> Record {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
+    pub fn with_partition(mut self, partition: u32) -> Record {
+        self.partition = Some(partition);
+        self
+    }
+
+    pub fn with_header<K: Into<String>, V: Into<Vec<u8>>>(mut self, key: K, value: V) -> Record {
+        self.headers.push(Header { key: key.into(), value: value.into() });
+        self
+    }
+}
+
+// Milliseconds since the epoch
+pub fn now_millis() -> u64 {
+    to_millis(SystemTime::now())
+}
+
+// Milliseconds since the epoch, or 0 for times before it
+pub fn to_millis(time: SystemTime) -> u64 {
+    let elapsed = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    elapsed.as_secs() * 1000 + elapsed.subsec_millis() as u64
+}
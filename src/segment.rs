@@ -1,52 +1,525 @@
-use std::fs::File;
+use std::fs::{self, File, OpenOptions};
 use std::mem;
 use std::path::PathBuf;
 use std::path::Path;
+use std::io;
 use std::io::prelude::*;
 use std::io::SeekFrom;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
 use crc::{crc32, Hasher32};
 
+use error::{Error, Result};
+use index::{self, OffsetIndex, TimeIndex};
+use record::{self, Header, Record};
+
+pub const DEFAULT_INDEX_INTERVAL_BYTES: u64 = 4096;
+
 pub struct Segment {
     path: PathBuf,
-    pub offset: usize,
+    pub base_offset: u64,
     buffer_size: usize,
+    // Format version of the file, which is only appended to in the current one
+    version: u32,
     file: Option<File>,
     write_buffer: Option<Vec<u8>>,
-    buffer_offset: usize
+    buffer_offset: usize,
+    size: u64,
+    num_messages: u64,
+    next_offset: u64,
+    created: SystemTime,
+    index: OffsetIndex,
+    index_interval_bytes: u64,
+    time_index: TimeIndex,
+    // Largest timestamp in the segment and the offset of the message carrying it
+    max_timestamp: Option<(u64, Offset)>,
+    // Position just past the last complete message, published once it is written, so readers of
+    // the active segment never look at a block the writer is part way through
+    committed: Arc<AtomicU64>
 }
 
 impl Segment {
-    pub fn new(path: &Path, offset: usize, buffer_size: usize) -> Segment {
+    pub fn new(path: &Path, base_offset: u64, buffer_size: usize) -> Segment {
         let path_buf = path.to_path_buf();
         Segment {
             path: path_buf,
-            offset: offset,
-            buffer_size: buffer_size,
+            base_offset,
+            buffer_size,
+            version: SEGMENT_VERSION,
             file: None,
             write_buffer: None,
-            buffer_offset: 0
+            buffer_offset: 0,
+            size: 0,
+            num_messages: 0,
+            next_offset: base_offset,
+            created: SystemTime::now(),
+            index: OffsetIndex::new(path, DEFAULT_INDEX_INTERVAL_BYTES),
+            index_interval_bytes: DEFAULT_INDEX_INTERVAL_BYTES,
+            time_index: TimeIndex::new(path),
+            max_timestamp: None,
+            committed: Arc::new(AtomicU64::new(u64::MAX))
+        }
+    }
+
+    // Sets how many bytes of messages go by between entries in the offset index
+    pub fn with_index_interval(mut self, interval_bytes: u64) -> Segment {
+        self.index = OffsetIndex::new(&self.path, interval_bytes);
+        self.index_interval_bytes = interval_bytes;
+        self
+    }
+
+    // Opens an existing segment file to continue appending to it, reporting anything dropped
+    // from its tail by recovery
+    pub fn reopen(path: &Path, base_offset: u64, buffer_size: usize) -> Result<(Segment, Option<RecoveryReport>)> {
+        let mut segment = Segment::new(path, base_offset, buffer_size);
+        let report = segment.open_for_append()?;
+        Ok((segment, report))
+    }
+
+    // Opens the segment file without truncating it, writing the file header if it is new. An
+    // existing file keeps the block size it was written with. Any existing messages are scanned so
+    // that writing resumes in the last block written, right after the last complete message, and
+    // the indexes are rebuilt along the way. Whatever follows that message, such as a chunk
+    // with a bad crc or a message missing its end, is left over from a torn write and gets truncated.
+    pub fn open_for_append(&mut self) -> Result<Option<RecoveryReport>> {
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&self.path)?;
+
+        if file.metadata()?.len() == 0 {
+            let header = SegmentHeader { version: SEGMENT_VERSION, block_size: self.buffer_size as u32, base_offset: self.base_offset };
+            header.write(&mut file)?;
+        } else {
+            let header = SegmentHeader::read(&mut file, &self.path)?;
+            self.buffer_size = header.block_size as usize;
+            self.version = header.version;
+        }
+        let mut buffer = vec![0; self.buffer_size];
+
+        let mut reader = SegmentReader::open(&self.path)?;
+        self.index = OffsetIndex::create(&self.path, self.index_interval_bytes)?;
+        self.time_index = TimeIndex::create(&self.path)?;
+        self.max_timestamp = None;
+        let mut num_messages = 0;
+        let mut next_offset = self.base_offset;
+        let mut corrupted = false;
+        for message in &mut reader {
+            match message {
+                Ok(message) => {
+                    self.index_message(message.offset, message.position, message.timestamp);
+                    num_messages += 1;
+                    next_offset = message.offset + 1;
+                },
+                Err(Error::Io(e)) => return Err(Error::Io(e)),
+                Err(_) => {
+                    corrupted = true;
+                    break;
+                }
+            }
+        }
+
+        let end = reader.position();
+        let file_len = file.metadata()?.len();
+        let file_end = SEGMENT_HEADER_BYTES + end;
+        let report = if corrupted || has_data_after(&mut file, &mut buffer, file_end, file_len)? {
+            file.set_len(file_end)?;
+            file.sync_all()?;
+
+            Some(RecoveryReport {
+                path: self.path.clone(),
+                valid_messages: num_messages,
+                truncated_at: file_end,
+                truncated_bytes: file_len - file_end,
+                corrupted
+            })
+        } else {
+            None
+        };
+
+        let block_size = self.buffer_size as u64;
+        let (size, buffer_offset) = if end == 0 {
+            (SEGMENT_HEADER_BYTES, 0)
+        } else {
+            let block_start = (end - 1) / block_size * block_size;
+            read_block(&mut file, &mut buffer, block_start)?;
+
+            let buffer_offset = (end - block_start) as usize;
+            clear_buffer(&mut buffer[buffer_offset..]);
+            (SEGMENT_HEADER_BYTES + block_start + block_size, buffer_offset)
+        };
+
+        // Writes seek back from the end of the last block written
+        file.seek(SeekFrom::Start(size))?;
+
+        if num_messages > 0 {
+            let metadata = file.metadata()?;
+            self.created = metadata.created().or_else(|_| metadata.modified())?;
         }
+
+        self.index.flush()?;
+        self.time_index.flush()?;
+
+        self.file = Some(file);
+        self.write_buffer = Some(buffer);
+        self.buffer_offset = buffer_offset;
+        self.size = size;
+        self.num_messages = num_messages;
+        self.next_offset = next_offset;
+        self.committed.store(end, Ordering::Release);
+        Ok(report)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
     }
 
-    pub fn append(&mut self, payload: &[u8]) {
+    pub fn block_size(&self) -> usize {
+        self.buffer_size
+    }
+
+    // Format version of the segment file, known once it has been opened for append
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    // Opens a reader that stops at the last message committed to the segment, even while it is
+    // still being appended to
+    pub fn reader(&self) -> Result<SegmentReader> {
+        let mut reader = SegmentReader::open(&self.path)?;
+        reader.committed = self.committed.clone();
+        Ok(reader)
+    }
+
+    // Loads the indexes of a sealed segment, rebuilding them if an index file is missing
+    pub fn open_index(&mut self) -> Result<()> {
+        self.size = fs::metadata(&self.path)?.len();
+
+        let index = OffsetIndex::load(&self.path, self.index_interval_bytes)?;
+        let time_index = TimeIndex::load(&self.path)?;
+        if let (Some(index), Some(time_index)) = (index, time_index) {
+            if let Some(next_offset) = self.indexed_next_offset(&index, &time_index)? {
+                self.max_timestamp = time_index.max_timestamp();
+                self.index = index;
+                self.time_index = time_index;
+                self.next_offset = next_offset;
+                // Compaction may have removed some of the offsets the segment spans
+                self.num_messages = next_offset - self.base_offset;
+                return Ok(());
+            }
+        }
+
+        self.index = OffsetIndex::create(&self.path, self.index_interval_bytes)?;
+        self.time_index = TimeIndex::create(&self.path)?;
+        self.max_timestamp = None;
+        self.num_messages = 0;
+        self.next_offset = self.base_offset;
+        for message in SegmentReader::open(&self.path)? {
+            match message {
+                Ok(message) => {
+                    self.index_message(message.offset, message.position, message.timestamp);
+                    self.num_messages += 1;
+                    self.next_offset = message.offset + 1;
+                },
+                Err(Error::Io(e)) => return Err(Error::Io(e)),
+                // Readers run into the corruption themselves
+                Err(_) => break,
+            }
+        }
+
+        self.close_indexes()
+    }
+
+    // Checks that loaded indexes belong to the segment as it is on disk, reading the messages after
+    // the last indexed one. Returns the offset following the segment's last message, or None if
+    // the indexes need rebuilding.
+    fn indexed_next_offset(&self, index: &OffsetIndex, time_index: &TimeIndex) -> Result<Option<Offset>> {
+        let data_bytes = self.size.saturating_sub(SEGMENT_HEADER_BYTES);
+        let (first, last) = match (index.first(), index.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return Ok(if data_bytes == 0 { Some(self.base_offset) } else { None }),
+        };
+
+        // The first message is always indexed
+        if first.0 < self.base_offset || first.1 != 0 || last.1 >= data_bytes {
+            return Ok(None);
+        }
+
+        let mut reader = SegmentReader::open(&self.path)?;
+        reader.seek(last.1);
+        let mut next_offset = None;
+        for message in reader {
+            match message {
+                Ok(message) => {
+                    if next_offset.is_none() && message.offset != last.0 {
+                        return Ok(None);
+                    }
+                    next_offset = Some(message.offset + 1);
+                },
+                Err(Error::Io(e)) => return Err(Error::Io(e)),
+                Err(_) => break,
+            }
+        }
+
+        let next_offset = match next_offset {
+            Some(next_offset) => next_offset,
+            None => return Ok(None),
+        };
+        match time_index.max_timestamp() {
+            Some((_, offset)) if offset < self.base_offset || offset >= next_offset => Ok(None),
+            _ => Ok(Some(next_offset)),
+        }
+    }
+
+    // Notes a message in the indexes, pairing a time index entry with each offset index entry
+    fn index_message(&mut self, offset: Offset, position: u64, timestamp: u64) {
+        if self.max_timestamp.map(|(max, _)| timestamp > max).unwrap_or(true) {
+            self.max_timestamp = Some((timestamp, offset));
+        }
+
+        if self.index.add(offset, position) {
+            if let Some((max, max_offset)) = self.max_timestamp {
+                self.time_index.add(max, max_offset);
+            }
+        }
+    }
+
+    // Writes out what is left of the indexes once nothing more is appended, ending the time index
+    // with the largest timestamp in the segment
+    fn close_indexes(&mut self) -> Result<()> {
+        if let Some((max, max_offset)) = self.max_timestamp {
+            self.time_index.add(max, max_offset);
+        }
+
+        self.index.sync()?;
+        self.time_index.sync()?;
+        self.index.close();
+        self.time_index.close();
+        Ok(())
+    }
+
+    // Position from which to read to find the message at `offset`
+    pub fn position_of(&self, offset: Offset) -> u64 {
+        self.index.lookup(offset).map(|(_, position)| position).unwrap_or(0)
+    }
+
+    // Largest timestamp of the messages in the segment
+    pub fn max_timestamp(&self) -> Option<u64> {
+        self.max_timestamp.map(|(max, _)| max)
+    }
+
+    // Offset from which to read to find the first message with a timestamp of at least `timestamp`
+    pub fn offset_to_search(&self, timestamp: u64) -> Offset {
+        self.time_index.lookup(timestamp).map(|offset| offset + 1).unwrap_or(self.base_offset)
+    }
+
+    pub fn append(&mut self, offset: u64, record: &Record) -> Result<()> {
+        self.append_batch(offset, &[record])
+    }
+
+    // Appends the records with consecutive offsets starting at `first_offset`, in a single write
+    pub fn append_batch(&mut self, first_offset: u64, records: &[&Record]) -> Result<()> {
         if self.file.is_none() {
-            let file = File::create(&self.path).unwrap();
-            self.file = Some(file);
-            self.write_buffer = Some(vec![0; self.buffer_size]);
-            self.buffer_offset = 0;
+            self.open_for_append()?;
         }
 
-        let file = self.file.as_mut().unwrap();
-        let mut buffer = self.write_buffer.as_mut().unwrap();
-        self.buffer_offset = write_payload(file, &mut buffer, self.buffer_offset, payload);
+        let now = record::now_millis();
+        let timestamps: Vec<u64> = records.iter().map(|record| record.timestamp.unwrap_or(now)).collect();
+        let mut messages = Vec::with_capacity(records.len());
+        for (i, record) in records.iter().enumerate() {
+            messages.push(encode_message(first_offset + i as u64, timestamps[i], record)?);
+        }
+
+        let (file, buffer) = match (self.file.as_mut(), self.write_buffer.as_mut()) {
+            (Some(file), Some(buffer)) => (file, buffer),
+            _ => unreachable!("Segment opened for append"),
+        };
+
+        let messages: Vec<&[u8]> = messages.iter().map(|message| message.as_slice()).collect();
+        let (buffer_offset, positions) = write_payloads(file, buffer, self.buffer_offset, &messages)?;
+        self.buffer_offset = buffer_offset;
+        self.size = file.stream_position()?;
+        self.committed.store(self.size - SEGMENT_HEADER_BYTES - self.buffer_size as u64 + buffer_offset as u64, Ordering::Release);
+
+        for (i, position) in positions.into_iter().enumerate() {
+            self.index_message(first_offset + i as u64, position - SEGMENT_HEADER_BYTES, timestamps[i]);
+        }
+        self.index.flush()?;
+        self.time_index.flush()?;
+
+        self.num_messages += records.len() as u64;
+        self.next_offset = first_offset + records.len() as u64;
+        Ok(())
+    }
+
+    // Offset following the last message in the segment
+    pub fn next_offset(&self) -> u64 {
+        self.next_offset
+    }
+
+    // Bytes written to the segment file, including its header
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn num_messages(&self) -> u64 {
+        self.num_messages
+    }
+
+    pub fn age(&self) -> Duration {
+        self.created.elapsed().unwrap_or_default()
     }
 
-    pub fn close(&mut self) {
+    // Flushes everything appended so far to disk
+    pub fn sync(&mut self) -> Result<()> {
+        if let Some(file) = self.file.as_mut() {
+            file.sync_data()?;
+        }
+
+        Ok(())
+    }
+
+    // Another handle to the segment file, for syncing it without access to the segment
+    pub fn sync_handle(&self) -> Result<Option<File>> {
+        match self.file {
+            Some(ref file) => Ok(Some(file.try_clone()?)),
+            None => Ok(None),
+        }
+    }
+
+    // When the segment file was last written to, which for a sealed segment is when it was sealed
+    pub fn last_modified(&self) -> Result<SystemTime> {
+        Ok(fs::metadata(&self.path)?.modified()?)
+    }
+
+    pub fn set_last_modified(&self, time: SystemTime) -> Result<()> {
+        OpenOptions::new().write(true).open(&self.path)?.set_modified(time)?;
+        Ok(())
+    }
+
+    // Moves the closed segment, along with its indexes, over the segment file at `path`. The
+    // segment file is replaced atomically. The old indexes are removed first, so a crash part way
+    // through leaves at worst a segment without indexes, which get rebuilt on open.
+    pub fn replace(&mut self, path: &Path) -> Result<()> {
+        self.close()?;
+
+        for index_path in &[index::index_path(path), index::time_index_path(path)] {
+            remove_if_exists(index_path)?;
+        }
+        fs::rename(&self.path, path)?;
+        fs::rename(index::index_path(&self.path), index::index_path(path))?;
+        fs::rename(index::time_index_path(&self.path), index::time_index_path(path))?;
+
+        self.path = path.to_path_buf();
+        self.open_index()
+    }
+
+    // Closes the segment and removes its file and indexes
+    pub fn delete(mut self) -> Result<()> {
         self.file = None;
         self.write_buffer = None;
+        self.index.close();
+        self.time_index.close();
+
+        fs::remove_file(&self.path)?;
+        for path in &[index::index_path(&self.path), index::time_index_path(&self.path)] {
+            remove_if_exists(path)?;
+        }
+
+        Ok(())
+    }
+
+    // Closes the segment file, syncing its indexes, which are otherwise rebuilt on reopen
+    pub fn close(&mut self) -> Result<()> {
+        if self.file.is_some() {
+            self.close_indexes()?;
+        }
+
+        self.file = None;
+        self.write_buffer = None;
+        Ok(())
     }
 }
 
+// Layout of the header at the start of every segment file. Blocks of chunks follow it, and
+// positions within a segment are counted from the end of the header.
+pub const SEGMENT_MAGIC_OFFSET: usize = 0;        // 0-3
+pub const SEGMENT_VERSION_OFFSET: usize = 4;      // 4-7
+pub const SEGMENT_BLOCK_SIZE_OFFSET: usize = 8;   // 8-11
+pub const SEGMENT_BASE_OFFSET_OFFSET: usize = 12; // 12-19
+pub const SEGMENT_CRC_OFFSET: usize = 20;         // 20-23
+
+pub const SEGMENT_HEADER_BYTES: u64 = 24;
+
+pub const SEGMENT_MAGIC: &[u8; 4] = b"QSEG";
+pub const SEGMENT_VERSION: u32 = 2;
+// Oldest version still read. Version 1 messages have no attributes byte.
+pub const MIN_SEGMENT_VERSION: u32 = 1;
+
+// The header written at the start of a segment file, describing how to read the rest of it
+#[derive(Debug, PartialEq)]
+pub struct SegmentHeader {
+    pub version: u32,
+    pub block_size: u32,
+    pub base_offset: u64
+}
+
+impl SegmentHeader {
+    // Reads and validates the header at the start of the file
+    pub fn read(file: &mut File, path: &Path) -> Result<SegmentHeader> {
+        let mut buffer = [0; SEGMENT_HEADER_BYTES as usize];
+
+        file.seek(SeekFrom::Start(0))?;
+        match file.read_exact(&mut buffer) {
+            Ok(()) => (),
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Err(Error::InvalidSegmentHeader(path.to_path_buf())),
+            Err(e) => return Err(Error::Io(e)),
+        }
+
+        let expected_crc = read_u32(&buffer, SEGMENT_CRC_OFFSET)?;
+        let is_valid = &buffer[SEGMENT_MAGIC_OFFSET..SEGMENT_VERSION_OFFSET] == SEGMENT_MAGIC &&
+            calculate_crc(&buffer[..SEGMENT_CRC_OFFSET]) == expected_crc;
+        if !is_valid {
+            return Err(Error::InvalidSegmentHeader(path.to_path_buf()));
+        }
+
+        let version = read_u32(&buffer, SEGMENT_VERSION_OFFSET)?;
+        if !(MIN_SEGMENT_VERSION..=SEGMENT_VERSION).contains(&version) {
+            return Err(Error::UnsupportedSegmentVersion { segment: path.to_path_buf(), version });
+        }
+
+        let block_size = read_u32(&buffer, SEGMENT_BLOCK_SIZE_OFFSET)?;
+        if !is_valid_block_size(block_size as usize) {
+            return Err(Error::InvalidSegmentHeader(path.to_path_buf()));
+        }
+
+        Ok(SegmentHeader { version, block_size, base_offset: read_u64(&buffer, SEGMENT_BASE_OFFSET_OFFSET)? })
+    }
+
+    // Reads the header of the segment file at `path`
+    pub fn read_path(path: &Path) -> Result<SegmentHeader> {
+        SegmentHeader::read(&mut File::open(path)?, path)
+    }
+
+    fn write(&self, file: &mut File) -> Result<()> {
+        let mut buffer = [0; SEGMENT_HEADER_BYTES as usize];
+
+        buffer[SEGMENT_MAGIC_OFFSET..SEGMENT_VERSION_OFFSET].copy_from_slice(SEGMENT_MAGIC);
+        write_u32(&mut buffer, self.version, SEGMENT_VERSION_OFFSET)?;
+        write_u32(&mut buffer, self.block_size, SEGMENT_BLOCK_SIZE_OFFSET)?;
+        write_u64(&mut buffer, self.base_offset, SEGMENT_BASE_OFFSET_OFFSET)?;
+        let crc = calculate_crc(&buffer[..SEGMENT_CRC_OFFSET]);
+        write_u32(&mut buffer, crc, SEGMENT_CRC_OFFSET)?;
+
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&buffer)?;
+        Ok(())
+    }
+}
+
+// Whether blocks of this size have room for a chunk
+pub fn is_valid_block_size(block_size: usize) -> bool {
+    block_size > NUM_HEADER_BYTES
+}
+
 pub const CRC_OFFSET: usize = 0;     // 0-3
 pub const LEN_OFFSET: usize = 4;     // 4-7
 pub const TYPE_OFFSET: usize = 8;    // 8
@@ -54,7 +527,23 @@ pub const PAYLOAD_OFFSET: usize = 9; // 9 - ??
 
 pub const NUM_HEADER_BYTES: usize = 9; // crc(4) + length(4) + type(1)
 
-#[derive(Copy, Clone)]
+// Layout of a message once its chunks have been stitched back together. The key is followed by
+// the number of headers(4), each header as a key and a value, and finally the payload. Keys and
+// header fields are written as length(4) then bytes.
+pub const MESSAGE_OFFSET_OFFSET: usize = 0;      // 0-7
+pub const MESSAGE_TIMESTAMP_OFFSET: usize = 8;   // 8-15
+pub const MESSAGE_ATTRIBUTES_OFFSET: usize = 16; // 16
+pub const MESSAGE_KEY_OFFSET: usize = 17;        // 17 - ??
+
+pub const MESSAGE_HEADER_BYTES: usize = 25; // offset(8) + timestamp(8) + attributes(1) + key length(4) + header count(4)
+
+// Attribute flags
+pub const ATTRIBUTE_TOMBSTONE: u8 = 1;
+
+// Key length of a message without a key, as opposed to one with an empty key
+const NULL_KEY_LEN: u32 = u32::MAX;
+
+#[derive(Copy, Clone, Debug)]
 pub enum ChunkType {
     Null = 0,
     Full = 1,
@@ -64,96 +553,276 @@ pub enum ChunkType {
 }
 
 impl ChunkType {
-    fn from_byte(x: u8) -> ChunkType {
+    fn from_byte(x: u8) -> Result<ChunkType> {
         match x {
-            x if x == ChunkType::Null as u8 => ChunkType::Null,
-            x if x == ChunkType::Full as u8 => ChunkType::Full,
-            x if x == ChunkType::Start as u8 => ChunkType::Start,
-            x if x == ChunkType::Middle as u8 => ChunkType::Middle,
-            x if x == ChunkType::End as u8 => ChunkType::End,
-            _ => panic!("Unknown chunk type"),
+            x if x == ChunkType::Null as u8 => Ok(ChunkType::Null),
+            x if x == ChunkType::Full as u8 => Ok(ChunkType::Full),
+            x if x == ChunkType::Start as u8 => Ok(ChunkType::Start),
+            x if x == ChunkType::Middle as u8 => Ok(ChunkType::Middle),
+            x if x == ChunkType::End as u8 => Ok(ChunkType::End),
+            _ => Err(Error::UnknownChunkType(x)),
         }
     }
 }
 
-fn write_payload(file: &mut File, buffer: &mut Vec<u8>, initial_buffer_offset: usize, payload: &[u8]) -> usize {
-    if payload.len() == 0 {
-        panic!("Can't handle empty messages");
+fn write_payload(file: &mut File, buffer: &mut [u8], initial_buffer_offset: usize, payload: &[u8]) -> Result<usize> {
+    write_payloads(file, buffer, initial_buffer_offset, &[payload]).map(|(buffer_offset, _)| buffer_offset)
+}
+
+// Packs the payloads into consecutive blocks and writes them with a single call. If the block
+// currently being filled has room, it is rewritten in place with the first payload appended.
+// Returns the new offset within the block being filled and the file position of each payload.
+fn write_payloads(file: &mut File, buffer: &mut [u8], initial_buffer_offset: usize, payloads: &[&[u8]]) -> Result<(usize, Vec<u64>)> {
+    if payloads.iter().any(|payload| payload.is_empty()) {
+        return Err(Error::EmptyMessage);
     }
 
-    let empty_vector = vec![];
+    let mut blocks = Vec::new();
+    let mut rewrites_last_block = false;
+    let mut buffer_offset = initial_buffer_offset;
+    let mut starts = Vec::with_capacity(payloads.len());
+    for payload in payloads {
+        let (next_buffer_offset, start) = encode_payload(&mut blocks, &mut rewrites_last_block, buffer, buffer_offset, payload)?;
+        buffer_offset = next_buffer_offset;
+        starts.push(start);
+    }
+
+    if rewrites_last_block {
+        file.seek(SeekFrom::Current(-(buffer.len() as i64)))?;
+    }
+    let write_position = file.stream_position()?;
+    file.write_all(&blocks)?;
 
+    let positions = starts.into_iter().map(|start| write_position + start as u64).collect();
+    Ok((buffer_offset, positions))
+}
+
+// Encodes the payload as chunks, appending each block it touches to `blocks`. Returns the new
+// offset within the block being filled and where in `blocks` the payload starts.
+fn encode_payload(blocks: &mut Vec<u8>, rewrites_last_block: &mut bool, buffer: &mut [u8], initial_buffer_offset: usize, payload: &[u8]) -> Result<(usize, usize)> {
     let mut remaining_payload = payload;
     let mut buffer_offset = initial_buffer_offset;
     let mut num_pre_chunks = 0;
+    let mut start = blocks.len();
 
     let has_buffer_space = initial_buffer_offset + NUM_HEADER_BYTES < buffer.len();
     let is_buffer_written = initial_buffer_offset > 0;
     if has_buffer_space && is_buffer_written {
         let open_buffer_size = buffer.len() - initial_buffer_offset - NUM_HEADER_BYTES;
-        // Last written chunk has room to append additional payload
-        file.seek(SeekFrom::Current(-(buffer.len() as i64))).expect("Failed to reset write location");
+        // Last written chunk has room to append additional payload. The block is either already in
+        // the file, or the last one encoded by this batch.
+        if blocks.is_empty() {
+            *rewrites_last_block = true;
+        } else {
+            let len = blocks.len() - buffer.len();
+            blocks.truncate(len);
+        }
+        start = blocks.len() + initial_buffer_offset;
         num_pre_chunks = 1;
 
         if remaining_payload.len() <= open_buffer_size {
             // Full write
-            buffer_offset = write_chunk(file, buffer, remaining_payload, 0, 1, buffer_offset);
-            remaining_payload = &empty_vector;
+            buffer_offset = write_chunk(blocks, buffer, remaining_payload, 0, 1, buffer_offset)?;
+            remaining_payload = &[];
         } else {
             // Partial write
             let chunk = &remaining_payload[0..open_buffer_size];
-            buffer_offset = write_chunk(file, buffer, chunk, 0, 2, buffer_offset); // Num chunks >= 2
+            buffer_offset = write_chunk(blocks, buffer, chunk, 0, 2, buffer_offset)?; // Num chunks >= 2
             remaining_payload = &remaining_payload[open_buffer_size..remaining_payload.len()];
         }
 
     }
 
-    if remaining_payload.len() > 0 {
+    if !remaining_payload.is_empty() {
         // 1. Exact
         // payload_size = 10
         // chunk_size = 10
         //
-        // (10 + 10 - 1) / 10
-        // 19 / 10
+        // 10.div_ceil(10)
         // 1 chunk
         //
         // 2. Partial
         // payload_size = 12
         // chunk_size = 10
         //
-        // (12 + 10 - 1) / 10
-        // 21 / 10
+        // 12.div_ceil(10)
         // 2 chunks
-        let num_payload_bytes_per_chunk = buffer.capacity() - NUM_HEADER_BYTES;
-        let num_chunks = num_pre_chunks + (remaining_payload.len() + num_payload_bytes_per_chunk - 1) / num_payload_bytes_per_chunk;
+        let num_payload_bytes_per_chunk = buffer.len() - NUM_HEADER_BYTES;
+        let num_chunks = num_pre_chunks + remaining_payload.len().div_ceil(num_payload_bytes_per_chunk);
 
-        let mut chunks_iter = remaining_payload.chunks(num_payload_bytes_per_chunk).enumerate();
-        while let Some((i, next_chunk)) = chunks_iter.next() {
-            buffer_offset = 0;
+        for (i, next_chunk) in remaining_payload.chunks(num_payload_bytes_per_chunk).enumerate() {
             clear_buffer(buffer);
 
-            buffer_offset = write_chunk(file, buffer, next_chunk, i + num_pre_chunks, num_chunks, buffer_offset);
+            buffer_offset = write_chunk(blocks, buffer, next_chunk, i + num_pre_chunks, num_chunks, 0)?;
         }
     }
 
-    file.flush().expect("Failed to flush");
-    file.sync_all().expect("Failed to sync");
+    Ok((buffer_offset, start))
+}
+
+// Bytes the record takes up in a segment when written as a single chunk, which is the least it
+// advances the segment by
+pub fn encoded_len(record: &Record) -> u64 {
+    (NUM_HEADER_BYTES + message_len(record)) as u64
+}
+
+fn message_len(record: &Record) -> usize {
+    let key_len = record.key.as_ref().map(|key| key.len()).unwrap_or(0);
+    let headers_len: usize = record.headers.iter().map(|header| 8 + header.key.len() + header.value.len()).sum();
+    let payload_len = if record.tombstone { 0 } else { record.payload.len() };
+    MESSAGE_HEADER_BYTES + key_len + headers_len + payload_len
+}
+
+fn encode_message(offset: u64, timestamp: u64, record: &Record) -> Result<Vec<u8>> {
+    let payload: &[u8] = if record.tombstone { &[] } else { &record.payload };
+    let mut message = vec![0; message_len(record)];
+
+    write_u64(&mut message, offset, MESSAGE_OFFSET_OFFSET)?;
+    write_u64(&mut message, timestamp, MESSAGE_TIMESTAMP_OFFSET)?;
+    message[MESSAGE_ATTRIBUTES_OFFSET] = if record.tombstone { ATTRIBUTE_TOMBSTONE } else { 0 };
+
+    let mut index = match record.key {
+        Some(ref key) => write_bytes(&mut message, key, MESSAGE_KEY_OFFSET)?,
+        None => {
+            write_u32(&mut message, NULL_KEY_LEN, MESSAGE_KEY_OFFSET)?;
+            MESSAGE_KEY_OFFSET + 4
+        }
+    };
+
+    write_u32(&mut message, record.headers.len() as u32, index)?;
+    index += 4;
+    for header in &record.headers {
+        index = write_bytes(&mut message, header.key.as_bytes(), index)?;
+        index = write_bytes(&mut message, &header.value, index)?;
+    }
+
+    message[index..].copy_from_slice(payload);
+    Ok(message)
+}
+
+// Decodes the message found at `position` in `segment`, written in `version` of the format,
+// which is corrupt if its fields don't fit
+fn decode_message(segment: &Path, version: u32, message: &[u8], position: u64) -> Result<Message> {
+    parse_message(version, message, position).ok_or_else(|| Error::Corruption { segment: segment.to_path_buf(), position })
+}
+
+fn parse_message(version: u32, message: &[u8], position: u64) -> Option<Message> {
+    let offset = read_u64(message, MESSAGE_OFFSET_OFFSET).ok()?;
+    let timestamp = read_u64(message, MESSAGE_TIMESTAMP_OFFSET).ok()?;
+
+    // Version 1 has no attributes, so the key starts where they would be
+    let (attributes, key_offset) = if version == 1 {
+        (0, MESSAGE_ATTRIBUTES_OFFSET)
+    } else {
+        (*message.get(MESSAGE_ATTRIBUTES_OFFSET)?, MESSAGE_KEY_OFFSET)
+    };
+    let tombstone = attributes & ATTRIBUTE_TOMBSTONE != 0;
+
+    let (key, mut index) = if read_u32(message, key_offset).ok()? == NULL_KEY_LEN {
+        (None, key_offset + 4)
+    } else {
+        let (key, index) = read_bytes(message, key_offset).ok()?;
+        (Some(key.to_vec()), index)
+    };
+
+    let num_headers = read_u32(message, index).ok()?;
+    index += 4;
+
+    let mut headers = Vec::new();
+    for _ in 0..num_headers {
+        let (key, next_index) = read_bytes(message, index).ok()?;
+        let (value, next_index) = read_bytes(message, next_index).ok()?;
+        let key = String::from_utf8(key.to_vec()).ok()?;
+
+        headers.push(Header { key, value: value.to_vec() });
+        index = next_index;
+    }
+
+    Some(Message { offset, position, key, timestamp, headers, tombstone, payload: message[index..].to_vec() })
+}
+
+// Writes a segment file in version 1 of the format, as releases before tombstones did
+#[cfg(test)]
+pub fn write_v1_segment(path: &Path, base_offset: Offset, block_size: usize, records: &[&Record]) -> Result<()> {
+    let mut file = File::create(path)?;
+    SegmentHeader { version: 1, block_size: block_size as u32, base_offset }.write(&mut file)?;
+
+    let mut messages = Vec::new();
+    for (i, record) in records.iter().enumerate() {
+        let mut message = encode_message(base_offset + i as u64, record.timestamp.unwrap_or(0), record)?;
+        message.remove(MESSAGE_ATTRIBUTES_OFFSET);
+        messages.push(message);
+    }
+
+    let messages: Vec<&[u8]> = messages.iter().map(|message| message.as_slice()).collect();
+    write_payloads(&mut file, &mut vec![0; block_size], 0, &messages)?;
+    Ok(())
+}
+
+// Writes `bytes` prefixed by their length, returning the index following them
+fn write_bytes(buffer: &mut [u8], bytes: &[u8], index: usize) -> Result<usize> {
+    write_u32(buffer, bytes.len() as u32, index)?;
+
+    let start = index + 4;
+    if start + bytes.len() > buffer.len() {
+        return Err(Error::OutOfBounds { index: start + bytes.len(), len: buffer.len() });
+    }
+    buffer[start..(start + bytes.len())].copy_from_slice(bytes);
+
+    Ok(start + bytes.len())
+}
+
+// Reads bytes prefixed by their length, returning them and the index following them
+fn read_bytes(buffer: &[u8], index: usize) -> Result<(&[u8], usize)> {
+    let start = index + 4;
+    let end = start + read_u32(buffer, index)? as usize;
+    if end > buffer.len() {
+        return Err(Error::OutOfBounds { index: end, len: buffer.len() });
+    }
+
+    Ok((&buffer[start..end], end))
+}
+
+fn remove_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => Ok(result?),
+    }
+}
+
+// Whether anything other than zero padding follows `position` in the file
+fn has_data_after(file: &mut File, buffer: &mut [u8], position: u64, file_len: u64) -> Result<bool> {
+    file.seek(SeekFrom::Start(position))?;
 
-    buffer_offset
+    let mut remaining = file_len.saturating_sub(position);
+    while remaining > 0 {
+        let num_read = file.read(buffer)?;
+        if num_read == 0 {
+            break;
+        }
+
+        if buffer[..num_read].iter().any(|x| *x != 0) {
+            return Ok(true);
+        }
+        remaining = remaining.saturating_sub(num_read as u64);
+    }
+
+    Ok(false)
 }
 
-fn clear_buffer(buffer: &mut Vec<u8>) {
+#[allow(clippy::needless_range_loop)]
+fn clear_buffer(buffer: &mut [u8]) {
     for i in 0..buffer.len() {
         buffer[i] = 0;
     }
 }
 
-fn write_chunk(file: &mut File, buffer: &mut Vec<u8>, payload: &[u8], chunk_index: usize, num_chunks: usize, buffer_offset: usize) -> usize {
+fn write_chunk(blocks: &mut Vec<u8>, buffer: &mut [u8], payload: &[u8], chunk_index: usize, num_chunks: usize, buffer_offset: usize) -> Result<usize> {
     let num_chunk_bytes: usize = payload.len() + NUM_HEADER_BYTES;
     let adjusted_offset = buffer_offset + num_chunk_bytes;
 
-    write_u32(buffer, 0, buffer_offset + CRC_OFFSET);
-    write_u32(buffer, payload.len() as u32, buffer_offset + LEN_OFFSET);
+    write_u32(buffer, 0, buffer_offset + CRC_OFFSET)?;
+    write_u32(buffer, payload.len() as u32, buffer_offset + LEN_OFFSET)?;
 
     buffer[buffer_offset + TYPE_OFFSET] = if chunk_index == 0 && num_chunks == 1 {
         ChunkType::Full as u8
@@ -165,65 +834,251 @@ fn write_chunk(file: &mut File, buffer: &mut Vec<u8>, payload: &[u8], chunk_inde
         ChunkType::Middle as u8
     };
 
-    let mut payload_iter = payload.iter().enumerate();
-    while let Some((i, x)) = payload_iter.next() {
-        buffer[buffer_offset + PAYLOAD_OFFSET + i] = *x;
-    }
+    let payload_start = buffer_offset + PAYLOAD_OFFSET;
+    buffer[payload_start..(payload_start + payload.len())].copy_from_slice(payload);
 
     let crc_start = buffer_offset + LEN_OFFSET; // Skip crc
     let record_crc = calculate_crc(&buffer[crc_start..adjusted_offset]);
 
-    write_u32(buffer, record_crc, buffer_offset + CRC_OFFSET);
+    write_u32(buffer, record_crc, buffer_offset + CRC_OFFSET)?;
 
-    file.write_all(&buffer).expect("Failed to write");
+    blocks.extend_from_slice(buffer);
 
-    adjusted_offset
+    Ok(adjusted_offset)
 }
 
-fn read_payload(file: &mut File, buffer: &mut Vec<u8>) -> Vec<u8> {
-    let mut payload = Vec::new();
+// What recovery cut from the tail of a segment
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecoveryReport {
+    pub path: PathBuf,
+    pub valid_messages: u64,
+    // Position in the file, which is its length after recovery
+    pub truncated_at: u64,
+    pub truncated_bytes: u64,
+    // Whether a bad chunk was found, rather than just a message missing its end
+    pub corrupted: bool
+}
 
-    loop {
-        let read_result = read_chunk(&mut payload, file, buffer).unwrap();
+pub type Offset = u64;
+
+#[derive(Debug, PartialEq)]
+pub struct Message {
+    pub offset: Offset,
+    pub(crate) position: u64,
+    pub key: Option<Vec<u8>>,
+    // Milliseconds since the epoch
+    pub timestamp: u64,
+    pub headers: Vec<Header>,
+    // Marks the key as deleted, in which case the payload is empty
+    pub tombstone: bool,
+    pub payload: Vec<u8>
+}
 
-        match read_result {
-            ChunkType::Full | ChunkType::End | ChunkType::Null => break,
-            ChunkType::Start | ChunkType::Middle => continue,
-        };
+impl Message {
+    // Bytes the message counts for against the limits of a fetch: its key and payload
+    pub fn num_bytes(&self) -> usize {
+        self.payload.len() + self.key.as_ref().map(|key| key.len()).unwrap_or(0)
     }
 
-    payload
+    // A record that appends a copy of the message
+    pub fn into_record(self) -> Record {
+        Record { key: self.key, payload: self.payload, timestamp: Some(self.timestamp), headers: self.headers, tombstone: self.tombstone, partition: None }
+    }
 }
 
-fn read_chunk(payload: &mut Vec<u8>, file: &mut File, buffer: &mut Vec<u8>) -> Result<ChunkType, &'static str> {
-    if let Result::Err(_) = file.read_exact(buffer) {
-        return Result::Err("Unable to read from file")
+// Walks the messages of a segment file, stitching chunks split across blocks back together.
+// Reaching the end of the file ends iteration, but a later call to `next` picks up anything
+// appended since. A reader opened through its `Segment` also stops at the segment's committed
+// position.
+pub struct SegmentReader {
+    path: PathBuf,
+    file: File,
+    version: u32,
+    buffer: Vec<u8>,
+    block_start: Option<u64>,
+    position: u64,
+    committed: Arc<AtomicU64>,
+    // Committed position when the buffered block was read; only the bytes before it can be trusted
+    loaded_committed: u64
+}
+
+impl SegmentReader {
+    // Opens the segment file, reading it in the block size recorded in its header
+    pub fn open(path: &Path) -> Result<SegmentReader> {
+        let mut file = File::open(path)?;
+        let header = SegmentHeader::read(&mut file, path)?;
+
+        let buffer = vec![0; header.block_size as usize];
+        let committed = Arc::new(AtomicU64::new(u64::MAX));
+        Ok(SegmentReader { path: path.to_path_buf(), file, version: header.version, buffer, block_start: None, position: 0, committed, loaded_committed: u64::MAX })
+    }
+
+    // Position of the next message to read
+    pub fn position(&self) -> u64 {
+        self.position
     }
 
-    let expected_crc: u32 = read_u32(&buffer, CRC_OFFSET).unwrap();
-    write_u32(buffer, 0, CRC_OFFSET);
-    let actual_crc = calculate_crc(buffer);
+    pub fn seek(&mut self, position: u64) {
+        self.position = position;
+    }
 
-    if expected_crc != actual_crc {
-        return Result::Err("CRC did not much expected value")
+    // Reads the next chunk at or after `position`, skipping any padding left at the end of a block.
+    // Returns the chunk's type, where it starts, and where the chunk after it starts.
+    fn read_chunk(&mut self, payload: &mut Vec<u8>, position: u64) -> Result<Option<(ChunkType, u64, u64)>> {
+        let block_size = self.buffer.len() as u64;
+        let mut position = position;
+
+        loop {
+            let block_start = position - position % block_size;
+            let chunk_offset = (position - block_start) as usize;
+
+            if chunk_offset + NUM_HEADER_BYTES > self.buffer.len() {
+                position = block_start + block_size;
+                continue;
+            }
+
+            if !self.load_block(block_start)? {
+                return Ok(None);
+            }
+
+            if self.buffer[chunk_offset + TYPE_OFFSET] == ChunkType::Null as u8 {
+                // Either padding, or the writer has not filled the rest of the block yet. The writer
+                // finishes a block before starting the next, so check for a later block before
+                // re-reading this one.
+                let has_next_block = self.load_block(block_start + block_size)?;
+
+                self.block_start = None;
+                self.load_block(block_start)?;
+                if self.buffer[chunk_offset + TYPE_OFFSET] != ChunkType::Null as u8 {
+                    continue;
+                }
+
+                if has_next_block {
+                    position = block_start + block_size;
+                    continue;
+                }
+
+                return Ok(None);
+            }
+
+            let buffer = &self.buffer;
+            let chunk_len = read_u32(buffer, chunk_offset + LEN_OFFSET)? as usize;
+            let chunk_end = chunk_offset + NUM_HEADER_BYTES + chunk_len;
+            if chunk_end > buffer.len() {
+                return Err(self.corruption(position));
+            }
+
+            let expected_crc = read_u32(buffer, chunk_offset + CRC_OFFSET)?;
+            let actual_crc = calculate_crc(&buffer[(chunk_offset + LEN_OFFSET)..chunk_end]);
+            if expected_crc != actual_crc {
+                return Err(self.corruption(position));
+            }
+
+            // Only trust the type once the crc has vouched for it
+            let chunk_type = ChunkType::from_byte(buffer[chunk_offset + TYPE_OFFSET])?;
+            payload.extend_from_slice(&buffer[(chunk_offset + PAYLOAD_OFFSET)..chunk_end]);
+
+            return Ok(Some((chunk_type, position, block_start + chunk_end as u64)));
+        }
     }
 
-    let chunk_len = read_u32(&buffer, LEN_OFFSET).unwrap() as usize;
-    let chunk_type = ChunkType::from_byte(buffer[TYPE_OFFSET]);
+    // Loads the block at `block_start` into the buffer, returning false if it lies past the end of the file
+    fn load_block(&mut self, block_start: u64) -> Result<bool> {
+        if self.block_start == Some(block_start) {
+            return Ok(true);
+        }
+
+        self.block_start = None;
+        if read_block(&mut self.file, &mut self.buffer, block_start)? == 0 {
+            return Ok(false);
+        }
 
-    payload.reserve(chunk_len);
-    for i in 0..chunk_len {
-        payload.push(buffer[PAYLOAD_OFFSET + i]);
+        self.block_start = Some(block_start);
+        Ok(true)
     }
 
-    Result::Ok(chunk_type)
+    fn corruption(&self, position: u64) -> Error {
+        Error::Corruption { segment: self.path.clone(), position }
+    }
 }
 
-pub fn read_u32(buffer: &[u8], index: usize) -> Result<u32, &'static str> {
+impl Iterator for SegmentReader {
+    type Item = Result<Message>;
+
+    fn next(&mut self) -> Option<Result<Message>> {
+        let committed = self.committed.load(Ordering::Acquire);
+        if self.position >= committed {
+            return None;
+        }
+
+        // Whatever was buffered may have been read mid-write, past the committed position of the time
+        if committed != self.loaded_committed {
+            self.block_start = None;
+            self.loaded_committed = committed;
+        }
+
+        let mut payload = Vec::new();
+        let mut message_position = None;
+        let mut position = self.position;
+
+        loop {
+            let (chunk_type, chunk_position, next_position) = match self.read_chunk(&mut payload, position) {
+                Ok(Some(chunk)) => chunk,
+                Ok(None) => {
+                    // The tail block may still be written to, so read it fresh next time
+                    self.block_start = None;
+                    return None;
+                },
+                Err(e) => return Some(Err(e)),
+            };
+
+            let is_first_chunk = message_position.is_none();
+            match (chunk_type, is_first_chunk) {
+                (ChunkType::Full, true) | (ChunkType::Start, true) | (ChunkType::Middle, false) | (ChunkType::End, false) => (),
+                _ => return Some(Err(self.corruption(chunk_position))),
+            }
+
+            let start = *message_position.get_or_insert(chunk_position);
+            position = next_position;
+
+            if let ChunkType::Full | ChunkType::End = chunk_type {
+                let message = match decode_message(&self.path, self.version, &payload, start) {
+                    Ok(message) => message,
+                    Err(e) => return Some(Err(e)),
+                };
+
+                self.position = position;
+                return Some(Ok(message));
+            }
+        }
+    }
+}
+
+// Reads the block starting at `block_start`, counted from the end of the segment header, zero
+// filling anything past the end of the file.
+fn read_block(file: &mut File, buffer: &mut [u8], block_start: u64) -> Result<usize> {
+    file.seek(SeekFrom::Start(SEGMENT_HEADER_BYTES + block_start))?;
+
+    let mut num_read = 0;
+    while num_read < buffer.len() {
+        match file.read(&mut buffer[num_read..]) {
+            Ok(0) => break,
+            Ok(n) => num_read += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(Error::Io(e)),
+        }
+    }
+
+    clear_buffer(&mut buffer[num_read..]);
+    Ok(num_read)
+}
+
+#[allow(clippy::needless_range_loop)]
+pub fn read_u32(buffer: &[u8], index: usize) -> Result<u32> {
     let size = mem::size_of::<u32>();
 
     if index + size > buffer.len() {
-        return Result::Err("Not enough readable bytes")
+        return Result::Err(Error::OutOfBounds { index, len: buffer.len() })
     }
 
     let mut result: u32 = 0;
@@ -235,11 +1090,12 @@ pub fn read_u32(buffer: &[u8], index: usize) -> Result<u32, &'static str> {
     Result::Ok(result)
 }
 
-pub fn write_u32(buffer: &mut Vec<u8>, x: u32, index: usize) -> Result<(), &'static str> {
+#[allow(clippy::needless_range_loop, clippy::assign_op_pattern)]
+pub fn write_u32(buffer: &mut [u8], x: u32, index: usize) -> Result<()> {
     let size = mem::size_of::<u32>();
 
     if index + size > buffer.len() {
-        return Result::Err("Not enough space to write")
+        return Result::Err(Error::OutOfBounds { index, len: buffer.len() })
     }
 
     let mut x_remain = x;
@@ -252,6 +1108,30 @@ pub fn write_u32(buffer: &mut Vec<u8>, x: u32, index: usize) -> Result<(), &'sta
     Result::Ok(())
 }
 
+pub fn read_u64(buffer: &[u8], index: usize) -> Result<u64> {
+    let size = mem::size_of::<u64>();
+
+    if index + size > buffer.len() {
+        return Result::Err(Error::OutOfBounds { index, len: buffer.len() })
+    }
+
+    let low = read_u32(buffer, index)? as u64;
+    let high = read_u32(buffer, index + mem::size_of::<u32>())? as u64;
+
+    Result::Ok((high << 32) | low)
+}
+
+pub fn write_u64(buffer: &mut [u8], x: u64, index: usize) -> Result<()> {
+    let size = mem::size_of::<u64>();
+
+    if index + size > buffer.len() {
+        return Result::Err(Error::OutOfBounds { index, len: buffer.len() })
+    }
+
+    write_u32(buffer, x as u32, index)?;
+    write_u32(buffer, (x >> 32) as u32, index + mem::size_of::<u32>())
+}
+
 fn calculate_crc(payload: &[u8]) -> u32 {
     let mut digest = crc32::Digest::new(crc32::IEEE);
     digest.write(payload);
@@ -288,6 +1168,18 @@ mod tests {
         assert_eq!(result, 1);
     }
 
+    #[test]
+    fn test_write_read_u64_cycle () {
+        let mut items: Vec<u8> = vec![0; 10];
+
+        write_u64(&mut items, 0x0102_0304_0506_0708, 2).expect("Should work");
+        assert_eq!(items, vec!(0, 0, 8, 7, 6, 5, 4, 3, 2, 1));
+
+        let result = read_u64(&items, 2).unwrap();
+        assert_eq!(result, 0x0102_0304_0506_0708);
+        assert!(read_u64(&items, 3).is_err());
+    }
+
     #[test]
     fn test_read_u32_with_overflow () {
         let items: Vec<u8> = vec![1, 0, 0, 0];
@@ -306,17 +1198,17 @@ mod tests {
     }
 
     fn write_messages_to_segment(path: &Path, buffer_size: usize, messages: &[&[u8]]) -> Vec<u8> {
-        fs::remove_file(&path);
-        let mut seg = Segment::new(path, 0, buffer_size);
+        fs::create_dir_all(path.parent().unwrap());
+        let mut file = File::create(path).unwrap();
+        let mut buffer = vec![0; buffer_size];
+        let mut buffer_offset = 0;
 
         for message in messages {
-            seg.append(&message);
+            buffer_offset = write_payload(&mut file, &mut buffer, buffer_offset, message).unwrap();
         }
 
-        seg.close();
-
-        let file = File::open(&path).unwrap();
-        let segment_bytes = file.bytes().map(|b| b.unwrap()).collect();
+        let mut segment_bytes = Vec::new();
+        File::open(path).unwrap().read_to_end(&mut segment_bytes).unwrap();
         segment_bytes
     }
 
@@ -327,6 +1219,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::needless_borrow)]
     fn test_single_append_full_initial() {
         let path = Path::new("./test_data/segments/test_single_append_full_initial");
         let message = vec![0, 1, 2, 3, 4];
@@ -337,6 +1230,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::needless_borrow)]
     fn test_single_append_split() {
         let path = Path::new("./test_data/segments/test_append_split");
         let message = vec![0, 1, 2, 3, 4, 5, 6, 7];
@@ -354,6 +1248,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::needless_borrow)]
     fn test_multi_append_full_initial() {
         let path = Path::new("./test_data/segments/test_multi_append_full_initial");
         let initial_message = vec![42];
@@ -373,6 +1268,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::needless_borrow)]
     fn test_multi_append_partial_initial() {
         let path = Path::new("./test_data/segments/test_multi_append_partial_initial");
         let initial_message = vec![42]; // 10 bytes
@@ -399,6 +1295,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::needless_borrow)]
     fn test_multi_append_none_initial() {
         let path = Path::new("./test_data/segments/test_multi_append_none_initial");
         let initial_message = vec![42];
@@ -416,4 +1313,383 @@ mod tests {
         let actual_secondary_message = &segment_bytes[secondary_message_offset + PAYLOAD_OFFSET..(secondary_message_offset + PAYLOAD_OFFSET + seconday_message.len())];
         assert_eq!(&seconday_message[0..seconday_message.len()], actual_secondary_message);
     }
+
+    #[test]
+    fn test_batch_matches_single_appends() {
+        let path = Path::new("./test_data/segments/test_batch_matches_single_appends");
+        let messages: Vec<&[u8]> = vec![&[42], &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13], &[7], &[1, 2, 3]];
+        let single_bytes = write_messages_to_segment(path, 32, &messages);
+
+        fs::remove_file(path);
+        let mut file = File::create(path).unwrap();
+        let mut buffer = vec![0; 32];
+        let buffer_offset = write_payload(&mut file, &mut buffer, 0, &[9]).unwrap();
+        write_payloads(&mut file, &mut buffer, buffer_offset, &messages[1..]).unwrap();
+
+        // Same layout, apart from the first message
+        let mut batch_bytes = Vec::new();
+        File::open(path).unwrap().read_to_end(&mut batch_bytes).unwrap();
+        assert_eq!(batch_bytes.len(), single_bytes.len());
+        assert_eq!(batch_bytes[10..], single_bytes[10..]);
+    }
+
+    #[test]
+    fn test_segment_reader() {
+        let path = Path::new("./test_data/segments/test_segment_reader");
+        fs::create_dir_all(path.parent().unwrap());
+        fs::remove_file(path);
+
+        let first_message = vec![42];
+        let second_message = vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19];
+        let third_message = vec![7, 7, 7];
+
+        let mut seg = Segment::new(path, 10, 32);
+        seg.append(10, &Record::new(first_message.clone())).unwrap();
+        seg.append(11, &Record::new(second_message.clone())).unwrap(); // Starts in the first block, ends in the second
+        seg.append(12, &Record::new(third_message.clone())).unwrap();
+        seg.close();
+
+        let messages: Vec<Message> = SegmentReader::open(path).unwrap().map(|m| m.unwrap()).collect();
+        let summaries: Vec<(u64, u64, &[u8])> = messages.iter().map(|m| (m.offset, m.position, m.payload.as_slice())).collect();
+        assert_eq!(summaries, vec![
+            (10, 0, first_message.as_slice()),
+            (11, 44, second_message.as_slice()),
+            (12, 116, third_message.as_slice()),
+        ]);
+    }
+
+    #[test]
+    fn test_record_round_trip() {
+        let path = Path::new("./test_data/segments/test_record_round_trip");
+        fs::create_dir_all(path.parent().unwrap());
+        fs::remove_file(path);
+
+        let record = Record::new(vec![1, 2, 3])
+            .with_key(vec![9, 9])
+            .with_timestamp(1_500_000_000_000)
+            .with_header("trace-id", vec![4, 2])
+            .with_header("empty", vec![]);
+        let empty_key = Record::new(vec![5]).with_key(vec![]);
+        let tombstone = Record::tombstone(vec![9, 9]);
+
+        let mut seg = Segment::new(path, 0, 32);
+        seg.append_batch(0, &[&record, &empty_key, &Record::new(vec![6]), &tombstone]).unwrap();
+        seg.close();
+
+        let messages: Vec<Message> = SegmentReader::open(path).unwrap().map(|m| m.unwrap()).collect();
+        assert_eq!(messages[0].key, Some(vec![9, 9]));
+        assert_eq!(messages[0].timestamp, 1_500_000_000_000);
+        assert_eq!(messages[0].headers, record.headers);
+        assert_eq!(messages[0].payload, vec![1, 2, 3]);
+
+        assert_eq!(messages[1].key, Some(vec![]));
+        assert_eq!(messages[2].key, None);
+        assert!(messages[2].headers.is_empty());
+        // Appended without a timestamp, so stamped with the time of the append
+        assert!(messages[2].timestamp > 1_500_000_000_000);
+
+        assert!(!messages[2].tombstone);
+        assert!(messages[3].tombstone);
+        assert_eq!(messages[3].key, Some(vec![9, 9]));
+        assert!(messages[3].payload.is_empty());
+    }
+
+    #[test]
+    fn test_segment_reader_resumes_after_append() {
+        let path = Path::new("./test_data/segments/test_segment_reader_resumes_after_append");
+        fs::create_dir_all(path.parent().unwrap());
+        fs::remove_file(path);
+
+        let mut seg = Segment::new(path, 0, 32);
+        seg.append(0, &Record::new(vec![1, 2, 3])).unwrap();
+
+        let mut reader = SegmentReader::open(path).unwrap();
+        assert_eq!(reader.next().unwrap().unwrap().payload, vec![1, 2, 3]);
+        assert!(reader.next().is_none());
+
+        seg.append(1, &Record::new(vec![4, 5, 6])).unwrap();
+        assert_eq!(reader.next().unwrap().unwrap().payload, vec![4, 5, 6]);
+
+        seg.append(2, &Record::new(vec![0; 40]));
+        assert_eq!(reader.next().unwrap().unwrap().payload, vec![0; 40]);
+        assert!(reader.next().is_none());
+
+        reader.seek(0);
+        assert_eq!(reader.count(), 3);
+    }
+
+    #[test]
+    fn test_segment_reader_stops_at_committed() {
+        let path = Path::new("./test_data/segments/test_segment_reader_stops_at_committed");
+        fs::create_dir_all(path.parent().unwrap());
+        fs::remove_file(path);
+
+        let mut seg = Segment::new(path, 0, 64);
+        seg.append(0, &Record::new(vec![1, 2, 3])).unwrap();
+
+        // A write still in flight, which has so far got half a chunk into the block
+        let mut file = OpenOptions::new().write(true).open(path).unwrap();
+        file.seek(SeekFrom::Start(SEGMENT_HEADER_BYTES + 40)).unwrap();
+        file.write_all(&[7; 10]).unwrap();
+
+        let mut reader = seg.reader().unwrap();
+        assert_eq!(reader.next().unwrap().unwrap().payload, vec![1, 2, 3]);
+        assert!(reader.next().is_none());
+
+        let mut unbounded = SegmentReader::open(path).unwrap();
+        unbounded.next();
+        match unbounded.next() {
+            Some(Err(Error::Corruption { .. })) => (),
+            _ => panic!("Expected corruption"),
+        }
+
+        seg.append(1, &Record::new(vec![4, 5, 6])).unwrap();
+        assert_eq!(reader.next().unwrap().unwrap().payload, vec![4, 5, 6]);
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn test_append_after_reopen() {
+        let path = Path::new("./test_data/segments/test_append_after_reopen");
+        fs::create_dir_all(path.parent().unwrap());
+        fs::remove_file(path);
+
+        let mut seg = Segment::new(path, 5, 32);
+        seg.append(5, &Record::new(vec![1, 2, 3])).unwrap();
+        seg.append(6, &Record::new(vec![4, 5])).unwrap();
+        seg.close();
+
+        let (mut seg, report) = Segment::reopen(path, 5, 32).unwrap();
+        assert_eq!(report, None);
+        assert_eq!(seg.num_messages(), 2);
+        assert_eq!(seg.next_offset(), 7);
+        assert_eq!(seg.size(), SEGMENT_HEADER_BYTES + 96);
+
+        seg.append(7, &Record::new(vec![6; 30]));
+        seg.close();
+
+        // Appending after close picks up where it left off rather than truncating
+        seg.append(8, &Record::new(vec![7])).unwrap();
+
+        let messages: Vec<Message> = SegmentReader::open(path).unwrap().map(|m| m.unwrap()).collect();
+        let offsets: Vec<u64> = messages.iter().map(|m| m.offset).collect();
+        assert_eq!(offsets, vec![5, 6, 7, 8]);
+        assert_eq!(messages[2].position, 96);
+        assert_eq!(messages[2].payload, vec![6; 30]);
+        assert_eq!(messages[3].payload, vec![7]);
+    }
+
+    #[test]
+    fn test_reopen_keeps_block_size() {
+        let path = Path::new("./test_data/segments/test_reopen_keeps_block_size");
+        fs::create_dir_all(path.parent().unwrap());
+        fs::remove_file(path);
+
+        let mut seg = Segment::new(path, 0, 32);
+        seg.append(0, &Record::new(vec![1; 20])).unwrap();
+        seg.close();
+
+        let (mut seg, _) = Segment::reopen(path, 0, 64).unwrap();
+        assert_eq!(seg.block_size(), 32);
+        seg.append(1, &Record::new(vec![2; 20])).unwrap();
+
+        let payloads: Vec<Vec<u8>> = SegmentReader::open(path).unwrap().map(|m| m.unwrap().payload).collect();
+        assert_eq!(payloads, vec![vec![1; 20], vec![2; 20]]);
+        assert_eq!(fs::metadata(path).unwrap().len(), SEGMENT_HEADER_BYTES + 128);
+    }
+
+    #[test]
+    fn test_reopen_drops_incomplete_message() {
+        let path = Path::new("./test_data/segments/test_reopen_drops_incomplete_message");
+        fs::create_dir_all(path.parent().unwrap());
+        fs::remove_file(path);
+
+        let mut seg = Segment::new(path, 0, 32);
+        seg.append(0, &Record::new(vec![1, 2, 3])).unwrap();
+        seg.append(1, &Record::new(vec![4; 60])); // Start, three Middles, End
+        seg.close();
+
+        // Lose the block holding the End chunk
+        let file = OpenOptions::new().write(true).open(path).unwrap();
+        file.set_len(SEGMENT_HEADER_BYTES + 96).unwrap();
+
+        let (mut seg, report) = Segment::reopen(path, 0, 32).unwrap();
+        assert_eq!(report, Some(RecoveryReport {
+            path: path.to_path_buf(),
+            valid_messages: 1,
+            truncated_at: SEGMENT_HEADER_BYTES + 46,
+            truncated_bytes: 50,
+            corrupted: false
+        }));
+        assert_eq!(fs::metadata(path).unwrap().len(), SEGMENT_HEADER_BYTES + 46);
+        assert_eq!(seg.next_offset(), 1);
+
+        seg.append(1, &Record::new(vec![5; 3]));
+        seg.close();
+
+        let payloads: Vec<Vec<u8>> = SegmentReader::open(path).unwrap().map(|m| m.unwrap().payload).collect();
+        assert_eq!(payloads, vec![vec![1, 2, 3], vec![5; 3]]);
+
+        let (_, report) = Segment::reopen(path, 0, 32).unwrap();
+        assert_eq!(report, None);
+    }
+
+    #[test]
+    fn test_segment_reader_with_bad_crc() {
+        let path = Path::new("./test_data/segments/test_segment_reader_with_bad_crc");
+        fs::create_dir_all(path.parent().unwrap());
+        fs::remove_file(path);
+
+        let mut seg = Segment::new(path, 0, 16);
+        seg.append(0, &Record::new(vec![0, 1, 2, 3, 4])).unwrap();
+        seg.close();
+
+        let mut segment_bytes = fs::read(path).unwrap();
+        segment_bytes[SEGMENT_HEADER_BYTES as usize + PAYLOAD_OFFSET] = 42;
+        fs::write(path, &segment_bytes).unwrap();
+
+        let mut reader = SegmentReader::open(path).unwrap();
+        match reader.next() {
+            Some(Err(Error::Corruption { segment, position })) => {
+                assert_eq!(segment, path);
+                assert_eq!(position, 0);
+            },
+            _ => panic!("Expected corruption"),
+        }
+    }
+
+    #[test]
+    fn test_decode_malformed_message() {
+        let path = Path::new("./test_data/segments/test_decode_malformed_message");
+
+        // Cut short before the attributes
+        match decode_message(path, SEGMENT_VERSION, &[0; MESSAGE_ATTRIBUTES_OFFSET], 3) {
+            Err(Error::Corruption { segment, position: 3 }) => assert_eq!(segment, path),
+            _ => panic!("Expected corruption"),
+        }
+
+        let record = Record::new(vec![1]).with_header("h", vec![2]);
+        let mut message = encode_message(0, 0, &record).unwrap();
+        assert_eq!(decode_message(path, SEGMENT_VERSION, &message, 0).unwrap().headers, record.headers);
+
+        // A header key that isn't UTF-8
+        message[MESSAGE_HEADER_BYTES + 4] = 0xff;
+        match decode_message(path, SEGMENT_VERSION, &message, 0) {
+            Err(Error::Corruption { .. }) => (),
+            _ => panic!("Expected corruption"),
+        }
+    }
+
+    #[test]
+    fn test_open_index() {
+        let dir = Path::new("./test_data/segments/test_open_index");
+        fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+
+        let write_segment = |name: &str, base_offset: Offset| {
+            let path = dir.join(name);
+            let mut seg = Segment::new(&path, base_offset, 64).with_index_interval(100);
+            for i in 0..20 {
+                seg.append(base_offset + i, &Record::new(vec![i as u8; 30])).unwrap();
+            }
+            seg.close().unwrap();
+            path
+        };
+        let path = write_segment("segment_000000010", 10);
+        let other_path = write_segment("segment_000000030", 30);
+
+        let open = |path: &Path| {
+            let mut seg = Segment::new(path, 10, 64).with_index_interval(100);
+            seg.open_index().unwrap();
+            seg
+        };
+
+        // Sealed segments pick up where they left off from their indexes
+        let seg = open(&path);
+        assert_eq!(seg.next_offset(), 30);
+        assert_eq!(seg.num_messages(), 20);
+        let index_len = fs::metadata(index::index_path(&path)).unwrap().len();
+        assert!(index_len > index::INDEX_ENTRY_BYTES as u64);
+
+        // An index belonging to another segment is rebuilt
+        fs::copy(index::index_path(&other_path), index::index_path(&path)).unwrap();
+        let seg = open(&path);
+        assert_eq!(seg.next_offset(), 30);
+        let mut reader = seg.reader().unwrap();
+        reader.seek(seg.position_of(25));
+        assert_eq!(reader.find(|message| message.as_ref().unwrap().offset >= 25).unwrap().unwrap().offset, 25);
+
+        // As is one indexing past the end of a segment cut short
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(SEGMENT_HEADER_BYTES + 64).unwrap();
+        let seg = open(&path);
+        assert_eq!(seg.next_offset(), 11);
+        assert_eq!(seg.num_messages(), 1);
+        assert!(fs::metadata(index::index_path(&path)).unwrap().len() < index_len);
+    }
+
+    #[test]
+    fn test_read_v1_segment() {
+        let path = Path::new("./test_data/segments/test_read_v1_segment");
+        fs::create_dir_all(path.parent().unwrap());
+        fs::remove_file(path);
+
+        let records = vec![Record::new("a").with_key("k").with_timestamp(1000), Record::new(vec![7; 40]).with_header("trace", "1").with_timestamp(1001)];
+        write_v1_segment(path, 5, 32, &records.iter().collect::<Vec<_>>()).unwrap();
+
+        let messages: Vec<Message> = SegmentReader::open(path).unwrap().map(|message| message.unwrap()).collect();
+        assert_eq!(messages.iter().map(|message| message.offset).collect::<Vec<_>>(), vec![5, 6]);
+        assert!(messages.iter().all(|message| !message.tombstone));
+        let read: Vec<Record> = messages.into_iter().map(Message::into_record).collect();
+        assert_eq!(read, records);
+
+        let (seg, report) = Segment::reopen(path, 5, 32).unwrap();
+        assert!(report.is_none());
+        assert_eq!(seg.version(), 1);
+        assert_eq!(seg.next_offset(), 7);
+    }
+
+    #[test]
+    fn test_segment_header() {
+        let path = Path::new("./test_data/segments/test_segment_header");
+        fs::create_dir_all(path.parent().unwrap());
+        fs::remove_file(path);
+
+        let mut seg = Segment::new(path, 7, 32);
+        seg.append(7, &Record::new(vec![1])).unwrap();
+        seg.close();
+
+        let header = SegmentHeader::read_path(path).unwrap();
+        assert_eq!(header, SegmentHeader { version: SEGMENT_VERSION, block_size: 32, base_offset: 7 });
+
+        let mut segment_bytes = fs::read(path).unwrap();
+        write_u32(&mut segment_bytes, 99, SEGMENT_VERSION_OFFSET).unwrap();
+        let crc = calculate_crc(&segment_bytes[..SEGMENT_CRC_OFFSET]);
+        write_u32(&mut segment_bytes, crc, SEGMENT_CRC_OFFSET).unwrap();
+        fs::write(path, &segment_bytes).unwrap();
+        match SegmentHeader::read_path(path) {
+            Err(Error::UnsupportedSegmentVersion { version: 99, .. }) => (),
+            _ => panic!("Expected unsupported version"),
+        }
+
+        segment_bytes[SEGMENT_MAGIC_OFFSET] = b'X';
+        fs::write(path, &segment_bytes).unwrap();
+        match SegmentHeader::read_path(path) {
+            Err(Error::InvalidSegmentHeader(_)) => (),
+            _ => panic!("Expected invalid header"),
+        }
+    }
+
+    #[test]
+    fn test_write_empty_payload() {
+        let path = Path::new("./test_data/segments/test_write_empty_payload");
+        fs::create_dir_all(path.parent().unwrap());
+
+        let mut file = File::create(path).unwrap();
+        let mut buffer = vec![0; 16];
+        match write_payload(&mut file, &mut buffer, 0, &[]) {
+            Err(Error::EmptyMessage) => (),
+            _ => panic!("Expected empty message error"),
+        }
+    }
 }
@@ -0,0 +1,909 @@
+use std::cmp;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use config::Acks;
+use consumer::Consumer;
+use error::{Error, Result};
+use kafka::Kafka;
+use offsets::CONSUMER_OFFSETS_TOPIC;
+use notify::{self, AppendNotifier};
+use protocol::{self, Decoder, Encoder, RequestHeader};
+use record::Record;
+use segment::{Message, Offset};
+
+// The only broker, leading every partition
+const NODE_ID: i32 = 0;
+
+// Largest request accepted, guarding against a bogus size allocating without bound
+const MAX_REQUEST_BYTES: usize = 100 * 1024 * 1024;
+
+// Longest allowed topic name, as in Kafka
+const MAX_TOPIC_NAME_LEN: usize = 249;
+
+// Told why a connection was closed, and who it was from if known
+type ConnectionErrorHandler = dyn Fn(Option<SocketAddr>, &Error) + Send + Sync;
+
+// Serves the topics to Kafka clients over TCP, speaking the subset of the protocol in `protocol`.
+// Every connection gets a thread, and requests on it are answered in order.
+pub struct Server {
+    kafka: Kafka,
+    advertised: Option<(String, u16)>,
+    on_connection_error: Option<Arc<ConnectionErrorHandler>>
+}
+
+impl Server {
+    pub fn new(kafka: Kafka) -> Server {
+        Server { kafka, advertised: None, on_connection_error: None }
+    }
+
+    // Sets the address clients are told to connect to, which defaults to the address listened on
+    pub fn with_advertised_address(mut self, host: &str, port: u16) -> Server {
+        self.advertised = Some((host.to_string(), port));
+        self
+    }
+
+    // Calls `handler` with the error each connection is closed on. Errors are dropped without one.
+    pub fn with_connection_error_handler<F>(mut self, handler: F) -> Server where F: Fn(Option<SocketAddr>, &Error) + Send + Sync + 'static {
+        self.on_connection_error = Some(Arc::new(handler));
+        self
+    }
+
+    // Accepts connections until the listener fails
+    pub fn serve(&self, listener: TcpListener) -> Result<()> {
+        let (host, port) = match self.advertised {
+            Some((ref host, port)) => (host.clone(), port),
+            None => {
+                let address = listener.local_addr()?;
+                (address.ip().to_string(), address.port())
+            },
+        };
+
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let handler = Handler { kafka: self.kafka.clone(), host: host.clone(), port: port as i32 };
+            let on_error = self.on_connection_error.clone();
+
+            thread::spawn(move || {
+                let peer = stream.peer_addr().ok();
+                if let (Err(e), Some(on_error)) = (handler.handle_connection(stream), on_error) {
+                    on_error(peer, &e);
+                }
+            });
+        }
+
+        Ok(())
+    }
+}
+
+struct Handler {
+    kafka: Kafka,
+    host: String,
+    port: i32
+}
+
+impl Handler {
+    fn handle_connection(&self, mut stream: TcpStream) -> Result<()> {
+        loop {
+            let mut size = [0; 4];
+            match stream.read_exact(&mut size) {
+                Ok(()) => (),
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(Error::Io(e)),
+            }
+
+            let size = i32::from_be_bytes(size);
+            if size < 0 || size as usize > MAX_REQUEST_BYTES {
+                return Err(Error::MalformedRequest);
+            }
+            let mut request = vec![0; size as usize];
+            stream.read_exact(&mut request)?;
+
+            let mut decoder = Decoder::new(&request);
+            let header = RequestHeader::read(&mut decoder)?;
+            let body = match self.handle_request(&header, &mut decoder)? {
+                Some(body) => body,
+                None => continue,
+            };
+
+            let mut response = Encoder::new();
+            response.write_i32((body.len() + 4) as i32);
+            response.write_i32(header.correlation_id);
+            stream.write_all(&response.into_bytes())?;
+            stream.write_all(&body)?;
+        }
+    }
+
+    // Returns the body of the response, or `None` for a request that gets no response
+    fn handle_request(&self, header: &RequestHeader, decoder: &mut Decoder) -> Result<Option<Vec<u8>>> {
+        let version = header.api_version;
+        let is_supported = protocol::SUPPORTED_APIS.iter()
+            .any(|&(api_key, min, max)| api_key == header.api_key && version >= min && version <= max);
+
+        // Clients that start out asking for a newer version retry with one the server supports
+        if header.api_key == protocol::API_VERSIONS && !is_supported {
+            return Ok(Some(api_versions(protocol::UNSUPPORTED_VERSION, 0)));
+        }
+        if !is_supported {
+            return Err(Error::UnsupportedRequest { api_key: header.api_key, api_version: version });
+        }
+
+        let body = match header.api_key {
+            protocol::API_PRODUCE => return self.produce(version, decoder),
+            protocol::API_FETCH => self.fetch(decoder)?,
+            protocol::API_LIST_OFFSETS => self.list_offsets(version, decoder)?,
+            protocol::API_METADATA => self.metadata(version, decoder)?,
+            protocol::API_OFFSET_COMMIT => self.offset_commit(version, decoder)?,
+            protocol::API_OFFSET_FETCH => self.offset_fetch(version, decoder)?,
+            protocol::API_FIND_COORDINATOR => self.find_coordinator(decoder)?,
+            _ => api_versions(protocol::NONE, version),
+        };
+        Ok(Some(body))
+    }
+
+    fn metadata(&self, version: i16, decoder: &mut Decoder) -> Result<Vec<u8>> {
+        // Version 0 asks for every topic with an empty list, later versions with a null one
+        let topic_names = match decoder.read_array_len()? {
+            None => None,
+            Some(0) if version == 0 => None,
+            Some(len) => Some((0..len).map(|_| decoder.read_string()).collect::<Result<Vec<String>>>()?),
+        };
+        let topic_names = topic_names.unwrap_or_else(|| {
+            let mut names = self.kafka.topic_names();
+            names.sort();
+            names
+        });
+
+        let mut encoder = Encoder::new();
+        encoder.write_array_len(1);
+        encoder.write_i32(NODE_ID);
+        encoder.write_string(&self.host);
+        encoder.write_i32(self.port);
+        // The rack ends the only broker's entry, and the controller follows the list of brokers
+        if version >= 1 {
+            encoder.write_nullable_string(None);
+            encoder.write_i32(NODE_ID);
+        }
+
+        encoder.write_array(&topic_names, |encoder, topic_name| {
+            // Like Kafka, topics are created on first mention
+            let num_partitions = if is_valid_topic_name(topic_name) {
+                self.kafka.topic_or_create(topic_name).map(|partitions| partitions.len()).map_err(|e| protocol::error_code(&e))
+            } else {
+                Err(protocol::INVALID_TOPIC_EXCEPTION)
+            };
+
+            encoder.write_i16(num_partitions.err().unwrap_or(protocol::NONE));
+            encoder.write_string(topic_name);
+            if version >= 1 {
+                encoder.write_bool(false); // Internal
+            }
+
+            let partitions: Vec<i32> = (0..num_partitions.unwrap_or(0) as i32).collect();
+            encoder.write_array(&partitions, |encoder, &partition| {
+                encoder.write_i16(protocol::NONE);
+                encoder.write_i32(partition);
+                encoder.write_i32(NODE_ID);
+                encoder.write_array(&[NODE_ID], |encoder, &replica| encoder.write_i32(replica));
+                encoder.write_array(&[NODE_ID], |encoder, &replica| encoder.write_i32(replica));
+            });
+        });
+
+        Ok(encoder.into_bytes())
+    }
+
+    // Appends each partition's records in one batch, with all replicas acks making them durable
+    fn produce(&self, version: i16, decoder: &mut Decoder) -> Result<Option<Vec<u8>>> {
+        let _transactional_id = decoder.read_nullable_string()?;
+        let acks = decoder.read_i16()?;
+        let _timeout_ms = decoder.read_i32()?;
+
+        let topics = decoder.read_array(|decoder| {
+            let topic_name = decoder.read_string()?;
+            let partitions = decoder.read_array(|decoder| {
+                let partition = decoder.read_i32()?;
+                let records = decoder.read_nullable_bytes()?.unwrap_or(&[]);
+                Ok((partition, self.produce_partition(&topic_name, partition, records, acks)))
+            })?;
+            Ok((topic_name, partitions))
+        })?;
+
+        if acks == 0 {
+            return Ok(None);
+        }
+
+        let mut encoder = Encoder::new();
+        encoder.write_array(&topics, |encoder, (topic_name, partitions)| {
+            encoder.write_string(topic_name);
+            encoder.write_array(partitions, |encoder, &(partition, ref result)| {
+                encoder.write_i32(partition);
+                match *result {
+                    Ok(base_offset) => {
+                        encoder.write_i16(protocol::NONE);
+                        encoder.write_i64(base_offset);
+                    },
+                    Err(code) => {
+                        encoder.write_i16(code);
+                        encoder.write_i64(-1);
+                    },
+                }
+                encoder.write_i64(-1); // Log append time, as the producer's timestamps are kept
+                if version >= 5 {
+                    let log_start_offset = self.kafka.log_start_offset(topic_name, partition as u32).map(|offset| offset as i64);
+                    encoder.write_i64(log_start_offset.unwrap_or(-1));
+                }
+            });
+        });
+        encoder.write_i32(0); // Throttle time
+
+        Ok(Some(encoder.into_bytes()))
+    }
+
+    // Returns the offset of the first record appended, or the error code for the partition
+    fn produce_partition(&self, topic_name: &str, partition: i32, records: &[u8], acks: i16) -> ::std::result::Result<i64, i16> {
+        if !is_valid_topic_name(topic_name) {
+            return Err(protocol::INVALID_TOPIC_EXCEPTION);
+        }
+        if partition < 0 {
+            return Err(protocol::UNKNOWN_TOPIC_OR_PARTITION);
+        }
+
+        let acks = if acks == -1 { Acks::Durable } else { Acks::Written };
+        let result = protocol::decode_record_batches(records).and_then(|records| {
+            let records: Vec<Record> = records.into_iter().map(|record| record.with_partition(partition as u32)).collect();
+            let mut producer = self.kafka.producer(topic_name)?.with_acks(acks);
+            producer.send_records(&records)
+        });
+
+        match result {
+            Ok(metadata) => Ok(metadata.first().map(|metadata| metadata.offset as i64).unwrap_or(-1)),
+            Err(ref e) => Err(protocol::error_code(e)),
+        }
+    }
+
+    // Reads each partition from the offset asked for, waiting up to the request's max wait for
+    // min bytes to turn up. A partition returns at least one message, even if it's over the
+    // partition's max bytes.
+    fn fetch(&self, decoder: &mut Decoder) -> Result<Vec<u8>> {
+        let _replica_id = decoder.read_i32()?;
+        let max_wait = Duration::from_millis(cmp::max(decoder.read_i32()?, 0) as u64);
+        let min_bytes = cmp::max(decoder.read_i32()?, 0) as usize;
+        let max_bytes = cmp::max(decoder.read_i32()?, 0) as usize;
+        let _isolation_level = decoder.read_i8()?;
+
+        let mut fetches = Vec::new();
+        let topics = decoder.read_array(|decoder| {
+            let topic_name = decoder.read_string()?;
+            let num_partitions = decoder.read_array(|decoder| {
+                let partition = decoder.read_i32()?;
+                let offset = decoder.read_i64()?;
+                let max_bytes = cmp::max(decoder.read_i32()?, 0) as usize;
+                fetches.push(PartitionFetch::new(&self.kafka, &topic_name, partition, offset, max_bytes));
+                Ok(())
+            })?.len();
+            Ok((topic_name, num_partitions))
+        })?;
+
+        let deadline = Instant::now() + max_wait;
+        let mut num_bytes = read_partitions(&mut fetches, max_bytes, 0);
+
+        // Waits on every partition that could still return more at once, stopping early if none can
+        while num_bytes < min_bytes && num_bytes < max_bytes {
+            let is_empty = fetches.iter().all(PartitionFetch::is_empty);
+            let watched: Vec<_> = fetches.iter().filter_map(|fetch| fetch.watch(is_empty)).collect();
+            if watched.is_empty() || !notify::wait_for_any(&watched, deadline) {
+                break;
+            }
+
+            num_bytes = read_partitions(&mut fetches, max_bytes, num_bytes);
+        }
+
+        let mut encoder = Encoder::new();
+        encoder.write_i32(0); // Throttle time
+        let mut fetches = fetches.into_iter();
+        encoder.write_array(&topics, |encoder, &(ref topic_name, num_partitions)| {
+            encoder.write_string(topic_name);
+            encoder.write_array_len(num_partitions);
+            for fetch in fetches.by_ref().take(num_partitions) {
+                let high_watermark = self.kafka.next_offset(topic_name, fetch.partition as u32).map(|offset| offset as i64).unwrap_or(-1);
+
+                encoder.write_i32(fetch.partition);
+                encoder.write_i16(fetch.error);
+                encoder.write_i64(high_watermark);
+                encoder.write_i64(high_watermark); // Last stable offset
+                encoder.write_array_len(0); // Aborted transactions
+                encoder.write_nullable_bytes(Some(&protocol::encode_record_batch(&fetch.messages)));
+            }
+        });
+
+        Ok(encoder.into_bytes())
+    }
+
+    fn list_offsets(&self, version: i16, decoder: &mut Decoder) -> Result<Vec<u8>> {
+        let _replica_id = decoder.read_i32()?;
+        if version >= 2 {
+            let _isolation_level = decoder.read_i8()?;
+        }
+
+        let topics = decoder.read_array(|decoder| {
+            let topic_name = decoder.read_string()?;
+            let partitions = decoder.read_array(|decoder| {
+                let partition = decoder.read_i32()?;
+                let timestamp = decoder.read_i64()?;
+                Ok((partition, self.list_offset(&topic_name, partition, timestamp)))
+            })?;
+            Ok((topic_name, partitions))
+        })?;
+
+        let mut encoder = Encoder::new();
+        if version >= 2 {
+            encoder.write_i32(0); // Throttle time
+        }
+        encoder.write_array(&topics, |encoder, (topic_name, partitions)| {
+            encoder.write_string(topic_name);
+            encoder.write_array(partitions, |encoder, &(partition, ref result)| {
+                encoder.write_i32(partition);
+                encoder.write_i16(result.as_ref().err().map(protocol::error_code).unwrap_or(protocol::NONE));
+                encoder.write_i64(-1); // Timestamp of the message found
+                encoder.write_i64(result.as_ref().map(|offset| *offset as i64).unwrap_or(-1));
+            });
+        });
+
+        Ok(encoder.into_bytes())
+    }
+
+    // -1 asks for the next offset to be produced, -2 for the first one still held, and anything
+    // else for the first message at or after that many milliseconds since the epoch, or the next
+    // offset if there is none
+    fn list_offset(&self, topic_name: &str, partition: i32, timestamp: i64) -> Result<Offset> {
+        if partition < 0 {
+            return Err(Error::PartitionNotFound { topic: topic_name.to_string(), partition: partition as u32 });
+        }
+
+        let partition = partition as u32;
+        match timestamp {
+            -1 => self.kafka.next_offset(topic_name, partition),
+            -2 => self.kafka.log_start_offset(topic_name, partition),
+            _ => {
+                let time = UNIX_EPOCH + Duration::from_millis(cmp::max(timestamp, 0) as u64);
+                self.kafka.consumer(topic_name, partition)?.seek_to_timestamp(time)
+            },
+        }
+    }
+
+    // Commits offsets for consumers assigning themselves partitions; group membership is not
+    // handled over the protocol, so the generation and member are not checked
+    fn offset_commit(&self, version: i16, decoder: &mut Decoder) -> Result<Vec<u8>> {
+        let group = decoder.read_string()?;
+        let _generation_id = decoder.read_i32()?;
+        let _member_id = decoder.read_string()?;
+        let _retention_time_ms = decoder.read_i64()?;
+
+        let topics = decoder.read_array(|decoder| {
+            let topic_name = decoder.read_string()?;
+            let partitions = decoder.read_array(|decoder| {
+                let partition = decoder.read_i32()?;
+                let offset = decoder.read_i64()?;
+                let _metadata = decoder.read_nullable_string()?;
+
+                let result = if partition < 0 || offset < 0 {
+                    Err(Error::OffsetOutOfRange(offset as u64))
+                } else {
+                    self.kafka.commit(&group, &topic_name, partition as u32, offset as Offset)
+                };
+                Ok((partition, result.err().as_ref().map(protocol::error_code).unwrap_or(protocol::NONE)))
+            })?;
+            Ok((topic_name, partitions))
+        })?;
+
+        let mut encoder = Encoder::new();
+        if version >= 3 {
+            encoder.write_i32(0); // Throttle time
+        }
+        encoder.write_array(&topics, |encoder, (topic_name, partitions)| {
+            encoder.write_string(topic_name);
+            encoder.write_array(partitions, |encoder, &(partition, error)| {
+                encoder.write_i32(partition);
+                encoder.write_i16(error);
+            });
+        });
+
+        Ok(encoder.into_bytes())
+    }
+
+    fn offset_fetch(&self, version: i16, decoder: &mut Decoder) -> Result<Vec<u8>> {
+        let group = decoder.read_string()?;
+
+        // A null list asks for every partition the group has committed for
+        let topics = match decoder.read_array_len()? {
+            Some(len) => {
+                let mut topics = Vec::with_capacity(len);
+                for _ in 0..len {
+                    let topic_name = decoder.read_string()?;
+                    let partitions = decoder.read_array(|decoder| decoder.read_i32())?;
+                    topics.push((topic_name, partitions));
+                }
+                topics
+            },
+            None => {
+                let mut topic_names = self.kafka.topic_names();
+                topic_names.sort();
+                topic_names.into_iter().map(|topic_name| {
+                    let num_partitions = self.kafka.num_partitions(&topic_name).unwrap_or(0);
+                    let partitions = (0..num_partitions)
+                        .filter(|&partition| self.kafka.committed(&group, &topic_name, partition).is_some())
+                        .map(|partition| partition as i32)
+                        .collect();
+                    (topic_name, partitions)
+                }).filter(|(_, partitions): &(String, Vec<i32>)| !partitions.is_empty()).collect()
+            },
+        };
+
+        let mut encoder = Encoder::new();
+        if version >= 3 {
+            encoder.write_i32(0); // Throttle time
+        }
+        encoder.write_array(&topics, |encoder, (topic_name, partitions)| {
+            encoder.write_string(topic_name);
+            encoder.write_array(partitions, |encoder, &partition| {
+                let committed = if partition >= 0 { self.kafka.committed(&group, topic_name, partition as u32) } else { None };
+
+                encoder.write_i32(partition);
+                encoder.write_i64(committed.map(|offset| offset as i64).unwrap_or(-1));
+                encoder.write_nullable_string(Some("")); // Metadata
+                encoder.write_i16(protocol::NONE);
+            });
+        });
+        if version >= 2 {
+            encoder.write_i16(protocol::NONE);
+        }
+
+        Ok(encoder.into_bytes())
+    }
+
+    // This broker coordinates every group
+    fn find_coordinator(&self, decoder: &mut Decoder) -> Result<Vec<u8>> {
+        let _group = decoder.read_string()?;
+
+        let mut encoder = Encoder::new();
+        encoder.write_i16(protocol::NONE);
+        encoder.write_i32(NODE_ID);
+        encoder.write_string(&self.host);
+        encoder.write_i32(self.port);
+        Ok(encoder.into_bytes())
+    }
+}
+
+fn api_versions(error: i16, version: i16) -> Vec<u8> {
+    let mut encoder = Encoder::new();
+    encoder.write_i16(error);
+    encoder.write_array(protocol::SUPPORTED_APIS, |encoder, &(api_key, min, max)| {
+        encoder.write_i16(api_key);
+        encoder.write_i16(min);
+        encoder.write_i16(max);
+    });
+    if version >= 1 {
+        encoder.write_i32(0); // Throttle time
+    }
+
+    encoder.into_bytes()
+}
+
+// Topic names become directory names, so they are held to Kafka's rules, and can't be the name
+// of the topic holding committed offsets
+fn is_valid_topic_name(topic_name: &str) -> bool {
+    topic_name != CONSUMER_OFFSETS_TOPIC && !topic_name.is_empty() && topic_name.len() <= MAX_TOPIC_NAME_LEN && topic_name != "." && topic_name != ".." &&
+        topic_name.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-')
+}
+
+// Reads on from each partition of a fetch in turn, up to `max_bytes` in all, returning the total
+// read. Like Kafka, only the first partition to return anything may go past the limits with its
+// first message, so a message too big for them can't hold up the partition.
+fn read_partitions(fetches: &mut [PartitionFetch], max_bytes: usize, mut num_bytes: usize) -> usize {
+    let mut is_empty = fetches.iter().all(PartitionFetch::is_empty);
+    for fetch in fetches {
+        num_bytes += fetch.read(max_bytes.saturating_sub(num_bytes), is_empty);
+        is_empty &= fetch.is_empty();
+    }
+
+    num_bytes
+}
+
+// The messages read so far for one partition of a fetch
+struct PartitionFetch {
+    partition: i32,
+    consumer: Option<Consumer>,
+    // Appends to the partition, and the offset the next one got as of the last read
+    appends: Option<(Arc<AppendNotifier>, Offset)>,
+    error: i16,
+    max_bytes: usize,
+    num_bytes: usize,
+    messages: Vec<Message>
+}
+
+impl PartitionFetch {
+    fn new(kafka: &Kafka, topic_name: &str, partition: i32, offset: i64, max_bytes: usize) -> PartitionFetch {
+        let consumer = if partition < 0 || offset < 0 {
+            Err(Error::OffsetOutOfRange(offset as u64))
+        } else {
+            kafka.consumer(topic_name, partition as u32).and_then(|mut consumer| consumer.seek(offset as Offset).map(|_| consumer))
+        };
+
+        let (consumer, error) = match consumer {
+            Ok(consumer) => (Some(consumer), protocol::NONE),
+            Err(ref e) => (None, protocol::error_code(e)),
+        };
+        PartitionFetch { partition, consumer, appends: None, error, max_bytes, num_bytes: 0, messages: Vec::new() }
+    }
+
+    // Reads what is already there, up to `max_bytes` more, returning how many bytes were read. The
+    // first message is read whatever its size if `may_exceed`.
+    fn read(&mut self, max_bytes: usize, may_exceed: bool) -> usize {
+        // Taken before reading, so an append made meanwhile ends the next wait
+        self.appends = self.consumer.as_ref().map(|consumer| consumer.appends());
+
+        let mut num_read = 0;
+        while self.has_room(num_read, max_bytes, may_exceed) {
+            let message = match self.consumer.as_mut().map(|consumer| consumer.poll()) {
+                Some(Ok(Some(message))) => message,
+                Some(Err(ref e)) => {
+                    self.fail(e);
+                    break;
+                },
+                _ => break,
+            };
+            num_read += self.add(message);
+        }
+
+        num_read
+    }
+
+    // The appends to wait on for more to read, unless the partition failed or is already full
+    fn watch(&self, may_exceed: bool) -> Option<(Arc<AppendNotifier>, Offset)> {
+        if self.consumer.is_none() || !self.has_room(0, usize::MAX, may_exceed) {
+            return None;
+        }
+
+        self.appends.clone()
+    }
+
+    // Whether to read another message, the first getting through regardless if `may_exceed`
+    fn has_room(&self, num_read: usize, max_bytes: usize, may_exceed: bool) -> bool {
+        (may_exceed && self.is_empty()) || (self.num_bytes < self.max_bytes && num_read < max_bytes)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    fn add(&mut self, message: Message) -> usize {
+        let num_bytes = message.num_bytes();
+        self.num_bytes += num_bytes;
+        self.messages.push(message);
+        num_bytes
+    }
+
+    // Stops reading the partition, keeping what was read before the error
+    fn fail(&mut self, error: &Error) {
+        self.consumer = None;
+        if self.messages.is_empty() {
+            self.error = protocol::error_code(error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::net::SocketAddr;
+    use std::path::Path;
+
+    fn start_server(path: &Path) -> (Kafka, SocketAddr) {
+        fs::remove_dir_all(path);
+        let kafka = Kafka::new(path).unwrap();
+        kafka.open().unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = Server::new(kafka.clone());
+        thread::spawn(move || server.serve(listener));
+
+        (kafka, address)
+    }
+
+    // Sends a request and returns the body of the response
+    fn request(stream: &mut TcpStream, api_key: i16, api_version: i16, body: Encoder) -> Vec<u8> {
+        let header = RequestHeader { api_key, api_version, correlation_id: 42, client_id: Some("test".to_string()) };
+        let mut request = Encoder::new();
+        header.write(&mut request);
+        let mut request = request.into_bytes();
+        request.extend_from_slice(&body.into_bytes());
+
+        stream.write_all(&(request.len() as i32).to_be_bytes()).unwrap();
+        stream.write_all(&request).unwrap();
+
+        let mut size = [0; 4];
+        stream.read_exact(&mut size).unwrap();
+        let mut response = vec![0; i32::from_be_bytes(size) as usize];
+        stream.read_exact(&mut response).unwrap();
+
+        assert_eq!(&response[..4], &42i32.to_be_bytes());
+        response.split_off(4)
+    }
+
+    #[test]
+    fn test_api_versions() {
+        let (_, address) = start_server(Path::new("./test_data/server/test_api_versions"));
+        let mut stream = TcpStream::connect(address).unwrap();
+
+        let response = request(&mut stream, protocol::API_VERSIONS, 1, Encoder::new());
+        let mut decoder = Decoder::new(&response);
+        assert_eq!(decoder.read_i16().unwrap(), protocol::NONE);
+        let apis = decoder.read_array(|decoder| Ok((decoder.read_i16()?, decoder.read_i16()?, decoder.read_i16()?))).unwrap();
+        assert_eq!(apis, protocol::SUPPORTED_APIS.to_vec());
+        assert_eq!(decoder.read_i32().unwrap(), 0);
+
+        // Newer clients start with a flexible version, and get the supported versions back
+        let response = request(&mut stream, protocol::API_VERSIONS, 3, Encoder::new());
+        assert_eq!(Decoder::new(&response).read_i16().unwrap(), protocol::UNSUPPORTED_VERSION);
+    }
+
+    #[test]
+    fn test_metadata() {
+        let (kafka, address) = start_server(Path::new("./test_data/server/test_metadata"));
+        kafka.create_topic("foo", 2).unwrap();
+        let mut stream = TcpStream::connect(address).unwrap();
+
+        let mut body = Encoder::new();
+        body.write_array(&["foo", "bar", "../x", "__consumer_offsets"], |encoder, topic_name| encoder.write_string(topic_name));
+        let response = request(&mut stream, protocol::API_METADATA, 1, body);
+
+        let mut decoder = Decoder::new(&response);
+        let brokers = decoder.read_array(|decoder| Ok((decoder.read_i32()?, decoder.read_string()?, decoder.read_i32()?, decoder.read_nullable_string()?))).unwrap();
+        assert_eq!(brokers, vec![(NODE_ID, "127.0.0.1".to_string(), address.port() as i32, None)]);
+        assert_eq!(decoder.read_i32().unwrap(), NODE_ID);
+
+        let topics = decoder.read_array(|decoder| {
+            let error = decoder.read_i16()?;
+            let topic_name = decoder.read_string()?;
+            let _internal = decoder.read_bool()?;
+            let partitions = decoder.read_array(|decoder| {
+                let _error = decoder.read_i16()?;
+                let partition = decoder.read_i32()?;
+                let _leader = decoder.read_i32()?;
+                decoder.read_array(|decoder| decoder.read_i32())?;
+                decoder.read_array(|decoder| decoder.read_i32())?;
+                Ok(partition)
+            })?;
+            Ok((error, topic_name, partitions))
+        }).unwrap();
+        assert_eq!(topics, vec![
+            (protocol::NONE, "foo".to_string(), vec![0, 1]),
+            (protocol::NONE, "bar".to_string(), vec![0]),
+            (protocol::INVALID_TOPIC_EXCEPTION, "../x".to_string(), vec![]),
+            (protocol::INVALID_TOPIC_EXCEPTION, "__consumer_offsets".to_string(), vec![])
+        ]);
+        assert_eq!(kafka.num_partitions("bar").unwrap(), 1);
+    }
+
+    #[test]
+    fn test_produce_and_fetch() {
+        let (kafka, address) = start_server(Path::new("./test_data/server/test_produce_and_fetch"));
+        let mut stream = TcpStream::connect(address).unwrap();
+
+        let messages: Vec<Message> = (0..3u8).map(|i| {
+            Message { offset: i as Offset, position: 0, key: Some(vec![i]), timestamp: 1000 + i as u64, headers: Vec::new(), tombstone: false, payload: vec![i; 3] }
+        }).collect();
+        let mut body = Encoder::new();
+        body.write_nullable_string(None);
+        body.write_i16(-1);
+        body.write_i32(1000);
+        body.write_array_len(1);
+        body.write_string("foo");
+        body.write_array_len(1);
+        body.write_i32(0);
+        body.write_nullable_bytes(Some(&protocol::encode_record_batch(&messages)));
+        let response = request(&mut stream, protocol::API_PRODUCE, 5, body);
+
+        let mut decoder = Decoder::new(&response);
+        assert_eq!(decoder.read_array_len().unwrap(), Some(1));
+        assert_eq!(decoder.read_string().unwrap(), "foo");
+        assert_eq!(decoder.read_array_len().unwrap(), Some(1));
+        assert_eq!(decoder.read_i32().unwrap(), 0);
+        assert_eq!(decoder.read_i16().unwrap(), protocol::NONE);
+        assert_eq!(decoder.read_i64().unwrap(), 0);
+        assert_eq!(kafka.next_offset("foo", 0).unwrap(), 3);
+
+        // Fetch from offset 1, waiting on min bytes until the producer thread adds another message
+        let producer = {
+            let kafka = kafka.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                kafka.produce_record("foo", &Record::new(vec![3; 3]).with_key(vec![3]).with_timestamp(1003)).unwrap();
+            })
+        };
+
+        let mut body = Encoder::new();
+        body.write_i32(-1);
+        body.write_i32(10_000);
+        body.write_i32(12);
+        body.write_i32(1024 * 1024);
+        body.write_i8(0);
+        body.write_array_len(1);
+        body.write_string("foo");
+        body.write_array_len(1);
+        body.write_i32(0);
+        body.write_i64(1);
+        body.write_i32(1024 * 1024);
+        let response = request(&mut stream, protocol::API_FETCH, 4, body);
+        producer.join().unwrap();
+
+        let mut decoder = Decoder::new(&response);
+        assert_eq!(decoder.read_i32().unwrap(), 0);
+        assert_eq!(decoder.read_array_len().unwrap(), Some(1));
+        assert_eq!(decoder.read_string().unwrap(), "foo");
+        assert_eq!(decoder.read_array_len().unwrap(), Some(1));
+        assert_eq!(decoder.read_i32().unwrap(), 0);
+        assert_eq!(decoder.read_i16().unwrap(), protocol::NONE);
+        assert_eq!(decoder.read_i64().unwrap(), 4);
+        assert_eq!(decoder.read_i64().unwrap(), 4);
+        assert_eq!(decoder.read_array_len().unwrap(), Some(0));
+
+        let records = protocol::decode_record_batches(decoder.read_nullable_bytes().unwrap().unwrap()).unwrap();
+        let expected: Vec<Record> = (1..4u8).map(|i| Record::new(vec![i; 3]).with_key(vec![i]).with_timestamp(1000 + i as u64)).collect();
+        assert_eq!(records, expected);
+
+        // Partitions that can't return anything don't hold the fetch for its wait
+        let mut body = Encoder::new();
+        body.write_i32(-1);
+        body.write_i32(10_000);
+        body.write_i32(1);
+        body.write_i32(1024 * 1024);
+        body.write_i8(0);
+        body.write_array_len(1);
+        body.write_string("foo");
+        body.write_array(&[(1, 0), (0, 10)], |encoder, &(partition, offset)| {
+            encoder.write_i32(partition);
+            encoder.write_i64(offset);
+            encoder.write_i32(1024 * 1024);
+        });
+        let start = Instant::now();
+        let response = request(&mut stream, protocol::API_FETCH, 4, body);
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        let mut decoder = Decoder::new(&response);
+        assert_eq!(decoder.read_i32().unwrap(), 0);
+        assert_eq!(decoder.read_array_len().unwrap(), Some(1));
+        assert_eq!(decoder.read_string().unwrap(), "foo");
+        let errors = decoder.read_array(|decoder| {
+            let partition = decoder.read_i32()?;
+            let error = decoder.read_i16()?;
+            let _high_watermark = decoder.read_i64()?;
+            let _last_stable_offset = decoder.read_i64()?;
+            let _aborted = decoder.read_array_len()?;
+            let _records = decoder.read_nullable_bytes()?;
+            Ok((partition, error))
+        }).unwrap();
+        assert_eq!(errors, vec![(1, protocol::UNKNOWN_TOPIC_OR_PARTITION), (0, protocol::OFFSET_OUT_OF_RANGE)]);
+
+        // Offsets by time, and from either end
+        let mut body = Encoder::new();
+        body.write_i32(-1);
+        body.write_array_len(1);
+        body.write_string("foo");
+        body.write_array(&[(0, 1002), (0, -1), (0, -2), (1, -1)], |encoder, &(partition, timestamp)| {
+            encoder.write_i32(partition);
+            encoder.write_i64(timestamp);
+        });
+        let response = request(&mut stream, protocol::API_LIST_OFFSETS, 1, body);
+
+        let mut decoder = Decoder::new(&response);
+        assert_eq!(decoder.read_array_len().unwrap(), Some(1));
+        assert_eq!(decoder.read_string().unwrap(), "foo");
+        let offsets = decoder.read_array(|decoder| {
+            let partition = decoder.read_i32()?;
+            let error = decoder.read_i16()?;
+            let _timestamp = decoder.read_i64()?;
+            Ok((partition, error, decoder.read_i64()?))
+        }).unwrap();
+        assert_eq!(offsets, vec![(0, protocol::NONE, 2), (0, protocol::NONE, 4), (0, protocol::NONE, 0), (1, protocol::UNKNOWN_TOPIC_OR_PARTITION, -1)]);
+    }
+
+    #[test]
+    fn test_fetch_max_bytes_across_partitions() {
+        let (kafka, address) = start_server(Path::new("./test_data/server/test_fetch_max_bytes_across_partitions"));
+        kafka.create_topic("foo", 3).unwrap();
+        for partition in 1..3 {
+            for i in 0..2u8 {
+                kafka.produce_record("foo", &Record::new(vec![i; 8]).with_partition(partition)).unwrap();
+            }
+        }
+        let mut stream = TcpStream::connect(address).unwrap();
+
+        // Returns the offsets fetched from each partition, with a 4 byte limit on the request
+        let mut fetch = |offsets: &[(i32, i64)]| {
+            let mut body = Encoder::new();
+            body.write_i32(-1);
+            body.write_i32(0);
+            body.write_i32(1);
+            body.write_i32(4);
+            body.write_i8(0);
+            body.write_array_len(1);
+            body.write_string("foo");
+            body.write_array(offsets, |encoder, &(partition, offset)| {
+                encoder.write_i32(partition);
+                encoder.write_i64(offset);
+                encoder.write_i32(1024 * 1024);
+            });
+            let response = request(&mut stream, protocol::API_FETCH, 4, body);
+
+            let mut decoder = Decoder::new(&response);
+            decoder.read_i32().unwrap();
+            decoder.read_array_len().unwrap();
+            decoder.read_string().unwrap();
+            decoder.read_array(|decoder| {
+                let partition = decoder.read_i32()?;
+                decoder.read_i16()?;
+                decoder.read_i64()?;
+                decoder.read_i64()?;
+                decoder.read_array_len()?;
+                let records = protocol::decode_record_batches(decoder.read_nullable_bytes()?.unwrap_or(&[]))?;
+                Ok((partition, records.len()))
+            }).unwrap()
+        };
+
+        // Only the first partition with anything to return goes past the limit, with one message
+        assert_eq!(fetch(&[(0, 0), (1, 0), (2, 0)]), vec![(0, 0), (1, 1), (2, 0)]);
+        assert_eq!(fetch(&[(2, 1), (1, 0)]), vec![(2, 1), (1, 0)]);
+    }
+
+    #[test]
+    fn test_offset_commit_and_fetch() {
+        let (kafka, address) = start_server(Path::new("./test_data/server/test_offset_commit_and_fetch"));
+        kafka.produce_batch("foo", &[&[1], &[2]]).unwrap();
+        let mut stream = TcpStream::connect(address).unwrap();
+
+        let mut body = Encoder::new();
+        body.write_string("group");
+        body.write_i32(-1);
+        body.write_string("");
+        body.write_i64(-1);
+        body.write_array_len(1);
+        body.write_string("foo");
+        body.write_array(&[(0, 2), (1, 0)], |encoder, &(partition, offset)| {
+            encoder.write_i32(partition);
+            encoder.write_i64(offset);
+            encoder.write_nullable_string(None);
+        });
+        let response = request(&mut stream, protocol::API_OFFSET_COMMIT, 2, body);
+
+        let mut decoder = Decoder::new(&response);
+        assert_eq!(decoder.read_array_len().unwrap(), Some(1));
+        assert_eq!(decoder.read_string().unwrap(), "foo");
+        let errors = decoder.read_array(|decoder| Ok((decoder.read_i32()?, decoder.read_i16()?))).unwrap();
+        assert_eq!(errors, vec![(0, protocol::NONE), (1, protocol::UNKNOWN_TOPIC_OR_PARTITION)]);
+        assert_eq!(kafka.committed("group", "foo", 0), Some(2));
+
+        // Asking for every partition the group committed for
+        let mut body = Encoder::new();
+        body.write_string("group");
+        body.write_i32(-1);
+        let response = request(&mut stream, protocol::API_OFFSET_FETCH, 2, body);
+
+        let mut decoder = Decoder::new(&response);
+        assert_eq!(decoder.read_array_len().unwrap(), Some(1));
+        assert_eq!(decoder.read_string().unwrap(), "foo");
+        let offsets = decoder.read_array(|decoder| {
+            let partition = decoder.read_i32()?;
+            let offset = decoder.read_i64()?;
+            let _metadata = decoder.read_nullable_string()?;
+            Ok((partition, offset, decoder.read_i16()?))
+        }).unwrap();
+        assert_eq!(offsets, vec![(0, 2, protocol::NONE)]);
+        assert_eq!(decoder.read_i16().unwrap(), protocol::NONE);
+    }
+}
@@ -1,71 +1,619 @@
+use std::collections::HashMap;
 use std::path::Path;
 use std::path::PathBuf;
 use std::fs::{self, DirEntry};
 use std::fs::File;
 use std::io;
+use std::ops::Deref;
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
-use segment::Segment;
+use config::{Acks, CleanupPolicy, SyncPolicy, TopicConfig};
+use error::{Error, Result};
+use group_commit::GroupCommit;
+use notify::AppendNotifier;
+use record::{self, Record};
+use segment::{self, Message, Offset, RecoveryReport, Segment, SegmentHeader, SegmentReader};
 
 pub struct Topic {
     dir: PathBuf,
     segments: Vec<Segment>,
     current_segment: Option<Segment>,
-    buffer_size: usize
+    config: TopicConfig,
+    next_offset: u64,
+    commit: Arc<GroupCommit>,
+    appends: Arc<AppendNotifier>,
+    unsynced_messages: u64,
+    last_sync: Instant,
+    recovery: Option<RecoveryReport>
+}
+
+// Location of the next message to read: its offset, the segment (by base offset) holding it,
+// and a reader positioned within that segment
+#[derive(Default)]
+pub struct Cursor {
+    segment: u64,
+    offset: u64,
+    reader: Option<SegmentReader>
+}
+
+impl Cursor {
+    // Offset of the next message to read
+    pub fn offset(&self) -> Offset {
+        self.offset
+    }
+
+    // Reads on from the open segment reader, returning `None` at the end of what has been committed
+    // to the segment. Needs no access to the topic, so callers sharing the topic can do the file
+    // reads without holding its lock.
+    pub fn read_next(&mut self) -> Result<Option<Message>> {
+        let offset = self.offset;
+        let reader = match self.reader.as_mut() {
+            Some(reader) => reader,
+            None => return Ok(None),
+        };
+
+        match reader.find(|message| message.as_ref().map(|m| m.offset >= offset).unwrap_or(true)) {
+            Some(Ok(message)) => {
+                self.offset = message.offset + 1;
+                Ok(Some(message))
+            },
+            Some(Err(e)) => Err(e),
+            None => Ok(None),
+        }
+    }
+}
+
+// A sync of the messages up to `next_offset`. Waiters share syncs through the topic's group
+// commit, so a sync started after a message was appended covers it for every waiter.
+pub struct PendingSync {
+    commit: Arc<GroupCommit>,
+    file: Option<File>,
+    next_offset: Offset
+}
+
+impl PendingSync {
+    // Blocks until every message up to `next_offset` is on disk
+    pub fn wait(self) -> Result<()> {
+        let file = self.file;
+        let next_offset = self.next_offset;
+
+        self.commit.commit(next_offset, || {
+            if let Some(ref file) = file {
+                file.sync_data()?;
+            }
+            Ok(next_offset)
+        })
+    }
+}
+
+// The sealed segments of a topic as they were when a compaction started
+pub struct Compaction {
+    dir: PathBuf,
+    index_interval_bytes: u64,
+    delete_retention: Duration,
+    segments: Vec<SealedSegment>
+}
+
+struct SealedSegment {
+    base_offset: Offset,
+    path: PathBuf,
+    block_size: usize,
+    sealed: SystemTime
+}
+
+// A cleaned copy of a sealed segment, waiting to replace it
+pub struct CleanedSegment {
+    segment: Segment,
+    sealed: SystemTime,
+    num_removed: u64
+}
+
+impl Compaction {
+    // Writes a cleaned copy of each segment that has messages to remove
+    pub fn clean(self) -> Result<Vec<CleanedSegment>> {
+        let mut latest = HashMap::new();
+        for segment in &self.segments {
+            for message in SegmentReader::open(&segment.path)? {
+                let message = message?;
+                if let Some(key) = message.key {
+                    latest.insert(key, message.offset);
+                }
+            }
+        }
+
+        let now = SystemTime::now();
+        let mut cleaned_segments = Vec::new();
+
+        for segment in &self.segments {
+            let mut cleaned_path = self.dir.clone();
+            cleaned_path.push(format!("cleaned_{:09}", segment.base_offset));
+
+            let mut cleaned = Segment::new(&cleaned_path, segment.base_offset, segment.block_size)
+                .with_index_interval(self.index_interval_bytes);
+            cleaned.open_for_append()?;
+
+            let tombstones_expired = now.duration_since(segment.sealed).map(|age| age > self.delete_retention).unwrap_or(false);
+            let mut num_removed = 0;
+            for message in SegmentReader::open(&segment.path)? {
+                let message = message?;
+                let is_latest = message.key.as_ref().map(|key| latest[key] == message.offset).unwrap_or(true);
+
+                if is_latest && !(message.tombstone && tombstones_expired) {
+                    cleaned.append(message.offset, &message.into_record())?;
+                } else {
+                    num_removed += 1;
+                }
+            }
+
+            if num_removed == 0 {
+                cleaned.delete()?;
+                continue;
+            }
+
+            cleaned.sync()?;
+            cleaned_segments.push(CleanedSegment { segment: cleaned, sealed: segment.sealed, num_removed });
+        }
+
+        Ok(cleaned_segments)
+    }
+}
+
+// Reads the message at `cursor` from the topic returned by `log`, which is only asked for the
+// topic while finding the segment to read, and not while reading it
+pub fn read<F, T>(log: F, cursor: &mut Cursor) -> Result<Option<Message>> where F: Fn() -> T, T: Deref<Target = Topic> {
+    loop {
+        if !log().open_reader(cursor)? {
+            return Ok(None);
+        }
+
+        if let Some(message) = cursor.read_next()? {
+            return Ok(Some(message));
+        }
+
+        if !log().next_segment(cursor) {
+            return Ok(None);
+        }
+    }
 }
 
 impl Topic {
-    pub fn new(path: &Path, buffer_size: usize) -> io::Result<Topic> {
+    pub fn new(path: &Path, config: TopicConfig) -> Result<Topic> {
+        if !segment::is_valid_block_size(config.block_size) {
+            return Err(Error::InvalidBlockSize(config.block_size));
+        }
+
         let path_buf = path.to_path_buf();
 
-        println!("Creating dir: {:?}", &path_buf);
-        try!(fs::create_dir_all(&path_buf));
+        fs::create_dir_all(&path_buf)?;
 
         let mut segments = Vec::new();
 
-        for entry in try!(fs::read_dir(&path_buf)) {
-            let entry = try!(entry);
+        for entry in fs::read_dir(&path_buf)? {
+            let entry = entry?;
             let path = entry.path();
 
             if !path.is_file() {
                 continue;
             }
 
+            // Index files share the segment's name, with an extension
+            if path.extension().is_some() {
+                continue;
+            }
+
             if let Some(file_name_str) = path.file_name().and_then(|n| n.to_str()) {
+                // Left over from a compaction that didn't finish
+                if file_name_str.starts_with("cleaned_") {
+                    Segment::new(&path, 0, config.block_size).delete()?;
+                    continue;
+                }
+
                 if file_name_str.starts_with("segment_") {
-                    let offset = file_name_str.replace("segment_", "").parse::<usize>().unwrap();
+                    let base_offset = match file_name_str.replace("segment_", "").parse::<u64>() {
+                        Ok(base_offset) => base_offset,
+                        Err(_) => return Err(Error::InvalidSegmentName(path.clone())),
+                    };
 
-                    println!("Found segment file: {:?}, and offset {}", file_name_str, offset);
+                    // Files cut short before their header was written hold no messages
+                    let block_size = if path.metadata()?.len() > 0 {
+                        let header = SegmentHeader::read_path(&path)?;
+                        if header.base_offset != base_offset {
+                            return Err(Error::InvalidSegmentHeader(path.clone()));
+                        }
+                        header.block_size as usize
+                    } else {
+                        config.block_size
+                    };
 
-                    let segment = Segment::new(&path, offset, buffer_size);
+                    let segment = Segment::new(&path, base_offset, block_size).with_index_interval(config.index_interval_bytes);
                     segments.push(segment);
                 }
             }
         }
 
-        let topic = Topic { dir: path_buf, segments: segments, current_segment: None, buffer_size: buffer_size };
+        segments.sort_by_key(|segment| segment.base_offset);
+
+        let num_sealed = segments.len().saturating_sub(1);
+        for segment in &mut segments[..num_sealed] {
+            segment.open_index()?;
+        }
+
+        // Keep appending to the last segment written, recovering it from any torn writes. One
+        // written in an older format is sealed instead, and the next produce starts a new one.
+        let (current_segment, recovery) = match segments.pop() {
+            Some(tail) => {
+                let mut segment = tail.with_index_interval(config.index_interval_bytes);
+                let recovery = segment.open_for_append()?;
+                if segment.version() < segment::SEGMENT_VERSION {
+                    segment.close()?;
+                    segments.push(segment);
+                    (None, recovery)
+                } else {
+                    (Some(segment), recovery)
+                }
+            },
+            None => (None, None)
+        };
+        let next_offset = current_segment.as_ref().or(segments.last()).map(|segment| segment.next_offset()).unwrap_or(0);
+
+        let topic = Topic {
+            dir: path_buf,
+            segments,
+            current_segment,
+            config,
+            next_offset,
+            commit: Arc::new(GroupCommit::new(next_offset)),
+            appends: Arc::new(AppendNotifier::new(next_offset)),
+            unsynced_messages: 0,
+            last_sync: Instant::now(),
+            recovery
+        };
         Ok(topic)
     }
 
-    pub fn produce(&mut self, message: &[u8]) -> Result<(), &'static str> {
-        if self.current_segment.is_none() {
-            let next_offset = self.segments.last().map(|segment| segment.offset + 1).unwrap_or(0);
+    // Appends the record, returning the offset assigned to it once `acks` is satisfied
+    pub fn produce(&mut self, record: &Record, acks: Acks) -> Result<Offset> {
+        self.produce_batch(&[record], acks).map(|offsets| offsets.start)
+    }
+
+    // Appends the records with a single write and at most one sync, returning the offsets
+    // assigned to them. A batch is never split across segments.
+    pub fn produce_batch(&mut self, records: &[&Record], acks: Acks) -> Result<Range<Offset>> {
+        let (offsets, sync) = self.append_batch(records, acks)?;
+        if let Some(sync) = sync {
+            sync.wait()?;
+        }
+
+        Ok(offsets)
+    }
+
+    // Like `produce_batch`, but rather than syncing hands back the sync that `acks` or the sync
+    // policy call for. Waiting on it once the topic is no longer locked lets producers on other
+    // threads append in the meantime and share the sync.
+    pub fn append_batch(&mut self, records: &[&Record], acks: Acks) -> Result<(Range<Offset>, Option<PendingSync>)> {
+        if records.is_empty() {
+            return Ok((self.next_offset..self.next_offset, None));
+        }
+
+        let batch_bytes = records.iter().map(|record| segment::encoded_len(record)).sum();
+        if self.should_roll(batch_bytes) {
+            self.roll()?;
+        }
+
+        let offset = self.next_offset;
+        let dir = &self.dir;
+        let block_size = self.config.block_size;
+        let index_interval_bytes = self.config.index_interval_bytes;
+        let segment = self.current_segment.get_or_insert_with(|| {
+            let mut path = PathBuf::from(dir);
+            path.push(format!("segment_{:09}", offset));
+
+            Segment::new(&path, offset, block_size).with_index_interval(index_interval_bytes)
+        });
+
+        segment.append_batch(offset, records)?;
+        self.next_offset += records.len() as u64;
+        self.unsynced_messages += records.len() as u64;
+        self.appends.appended(self.next_offset);
+
+        let sync = if acks == Acks::Durable || self.is_sync_due() {
+            Some(self.pending_sync()?)
+        } else {
+            None
+        };
+
+        Ok((offset..self.next_offset, sync))
+    }
+
+    fn is_sync_due(&self) -> bool {
+        match self.config.sync_policy {
+            SyncPolicy::EveryMessage => true,
+            SyncPolicy::EveryMessages(n) => self.unsynced_messages >= n,
+            SyncPolicy::Interval(interval) => self.last_sync.elapsed() >= interval,
+            SyncPolicy::Never => false,
+        }
+    }
+
+    // Syncs everything produced so far to disk
+    pub fn sync(&mut self) -> Result<()> {
+        self.pending_sync()?.wait()
+    }
+
+    // A sync of everything produced so far, to wait on with or without the topic. Earlier segments
+    // were synced when they were sealed, so only the active one needs it.
+    pub fn pending_sync(&mut self) -> Result<PendingSync> {
+        let file = match self.current_segment {
+            Some(ref segment) => segment.sync_handle()?,
+            None => None,
+        };
 
-            let mut path = PathBuf::from(&self.dir);
-            path.push(format!("segment_{:09}", next_offset));
+        self.unsynced_messages = 0;
+        self.last_sync = Instant::now();
+        Ok(PendingSync { commit: self.commit.clone(), file, next_offset: self.next_offset })
+    }
+
+    // Every offset below this has been synced to disk
+    pub fn durable_offset(&self) -> Offset {
+        self.commit.durable_offset()
+    }
 
-            let segment = Segment::new(&path, next_offset, self.buffer_size);
-            self.current_segment = Some(segment);
+    // How many times the active segments have been synced, with concurrent producers sharing a sync
+    pub fn num_syncs(&self) -> u64 {
+        self.commit.num_syncs()
+    }
+
+    // Deletes sealed segments that have fallen outside the retention limits, oldest first,
+    // returning how many were deleted. The active segment is always kept.
+    pub fn enforce_retention(&mut self) -> Result<usize> {
+        if self.config.cleanup_policy != CleanupPolicy::Delete {
+            return Ok(0);
         }
 
-        let mut segment = self.current_segment.as_mut().unwrap();
-        segment.append(message);
+        let now = record::now_millis();
+        let mut total_bytes: u64 = self.all_segments().map(|segment| segment.size()).sum();
+        let mut num_deleted = 0;
+
+        while !self.segments.is_empty() {
+            let segment = &self.segments[0];
+
+            let is_expired = self.config.retention_age.map(|age| {
+                segment.max_timestamp().map(|max| now.saturating_sub(max) > age.as_millis() as u64).unwrap_or(false)
+            }).unwrap_or(false);
+            let is_over_size = self.config.retention_bytes.map(|max| total_bytes > max).unwrap_or(false);
+            if !is_expired && !is_over_size {
+                break;
+            }
+
+            let segment = self.segments.remove(0);
+            total_bytes -= segment.size();
+            segment.delete()?;
+            num_deleted += 1;
+        }
+
+        Ok(num_deleted)
+    }
+
+    // Rewrites the sealed segments keeping only the latest record for each key, returning how many
+    // messages were removed. Records without a key are kept, as are tombstones until the segment
+    // holding them has been sealed for longer than the delete retention. Offsets are preserved and
+    // each cleaned segment is swapped in atomically, so readers part way through the old file
+    // carry on undisturbed.
+    pub fn compact(&mut self) -> Result<u64> {
+        let cleaned = self.start_compaction()?.clean()?;
+        self.finish_compaction(cleaned)
+    }
+
+    // Takes note of the sealed segments to compact. They are never appended to, so they can be
+    // cleaned without holding on to the topic.
+    pub fn start_compaction(&self) -> Result<Compaction> {
+        let segments = self.segments.iter()
+            .map(|segment| Ok(SealedSegment {
+                base_offset: segment.base_offset,
+                path: segment.path().to_path_buf(),
+                block_size: segment.block_size(),
+                sealed: segment.last_modified()?
+            }))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Compaction {
+            dir: self.dir.clone(),
+            index_interval_bytes: self.config.index_interval_bytes,
+            delete_retention: self.config.delete_retention,
+            segments
+        })
+    }
+
+    // Swaps the cleaned segments in for the ones they were cleaned from, returning how many
+    // messages were removed
+    pub fn finish_compaction(&mut self, cleaned: Vec<CleanedSegment>) -> Result<u64> {
+        let mut num_removed = 0;
+
+        for mut cleaned in cleaned {
+            let segment = match self.segments.iter_mut().find(|segment| segment.base_offset == cleaned.segment.base_offset) {
+                Some(segment) => segment,
+                None => {
+                    cleaned.segment.delete()?;
+                    continue;
+                },
+            };
+
+            cleaned.segment.replace(segment.path())?;
+            cleaned.segment.set_last_modified(cleaned.sealed)?;
+            *segment = cleaned.segment;
+            num_removed += cleaned.num_removed;
+        }
+
+        Ok(num_removed)
+    }
+
+    // The first offset still held by the topic
+    pub fn log_start_offset(&self) -> Offset {
+        self.all_segments().next().map(|segment| segment.base_offset).unwrap_or(self.next_offset)
+    }
+
+    // A cursor from which the next read returns the message at `offset`
+    pub fn cursor_at(&self, offset: Offset) -> Result<Cursor> {
+        if offset < self.log_start_offset() || offset > self.next_offset {
+            return Err(Error::OffsetOutOfRange(offset));
+        }
+
+        Ok(Cursor { segment: self.segment_for(offset).map(|segment| segment.base_offset).unwrap_or(0), offset, reader: None })
+    }
+
+    // The segment that holds `offset`, if it has been written
+    fn segment_for(&self, offset: Offset) -> Option<&Segment> {
+        match self.current_segment {
+            Some(ref segment) if segment.base_offset <= offset => Some(segment),
+            _ => match self.segments.partition_point(|segment| segment.base_offset <= offset) {
+                0 => None,
+                i => Some(&self.segments[i - 1]),
+            },
+        }
+    }
+
+    // The first offset whose message has a timestamp at or after `timestamp`, in milliseconds
+    // since the epoch, or the next offset if there is none
+    pub fn offset_for_timestamp(&self, timestamp: u64) -> Result<Offset> {
+        for segment in self.all_segments() {
+            match segment.max_timestamp() {
+                Some(max) if max >= timestamp => (),
+                _ => continue,
+            }
+
+            let start = segment.offset_to_search(timestamp);
+            let mut reader = SegmentReader::open(segment.path())?;
+            reader.seek(segment.position_of(start));
+
+            for message in reader {
+                let message = message?;
+                if message.offset >= start && message.timestamp >= timestamp {
+                    return Ok(message.offset);
+                }
+            }
+        }
+
+        Ok(self.next_offset)
+    }
+
+    pub fn next_offset(&self) -> Offset {
+        self.next_offset
+    }
+
+    // Signals each append, for readers to wait on without holding on to the topic
+    pub fn appends(&self) -> Arc<AppendNotifier> {
+        self.appends.clone()
+    }
+
+    // What was dropped from the tail segment when the topic was opened, if anything
+    pub fn recovery_report(&self) -> Option<&RecoveryReport> {
+        self.recovery.as_ref()
+    }
+
+    // Reads the message at `cursor`, moving on to later segments once the current one is exhausted
+    pub fn read(&self, cursor: &mut Cursor) -> Result<Option<Message>> {
+        read(|| self, cursor)
+    }
+
+    // Points the cursor at the segment holding its offset, opening a reader on it if need be.
+    // Returns false if there is nothing to read yet.
+    pub fn open_reader(&self, cursor: &mut Cursor) -> Result<bool> {
+        let segment = match self.all_segments().find(|segment| segment.base_offset >= cursor.segment) {
+            Some(segment) => segment,
+            None => return Ok(false),
+        };
+
+        // The cursor's segment has been deleted. Unless every message in it was read, the cursor
+        // has lost its place.
+        if segment.base_offset != cursor.segment {
+            if cursor.offset < segment.base_offset {
+                return Err(Error::OffsetOutOfRange(cursor.offset));
+            }
+            *cursor = Cursor { segment: segment.base_offset, offset: cursor.offset, reader: None };
+        }
+
+        if cursor.reader.is_none() {
+            cursor.reader = match segment.reader() {
+                Ok(mut reader) => {
+                    reader.seek(segment.position_of(cursor.offset));
+                    Some(reader)
+                },
+                Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::NotFound => None,
+                Err(e) => return Err(e),
+            };
+        }
+
+        Ok(true)
+    }
+
+    // Moves the cursor on to the segment after the one it has read to the end of, returning false
+    // if there is none. A cursor that came up short of the segment's last message, because it read
+    // the segment before the latest append was committed, is left to read it again.
+    pub fn next_segment(&self, cursor: &mut Cursor) -> bool {
+        let segment = match self.all_segments().find(|segment| segment.base_offset == cursor.segment) {
+            Some(segment) => segment,
+            None => return true,
+        };
+
+        if cursor.reader.is_some() && cursor.offset < segment.next_offset() {
+            return true;
+        }
+
+        match self.all_segments().find(|next| next.base_offset > segment.base_offset) {
+            // Whatever lay between the two segments was compacted away
+            Some(next) => {
+                *cursor = Cursor { segment: next.base_offset, offset: cursor.offset.max(next.base_offset), reader: None };
+                true
+            },
+            None => false,
+        }
+    }
+
+    // Whether appending `num_bytes` would take the active segment past one of the configured limits
+    fn should_roll(&self, num_bytes: u64) -> bool {
+        let segment = match self.current_segment.as_ref() {
+            Some(segment) if segment.num_messages() > 0 => segment,
+            _ => return false,
+        };
+
+        let config = &self.config;
+        config.segment_bytes.map(|max| segment.size() + num_bytes > max).unwrap_or(false) ||
+            config.segment_messages.map(|max| segment.num_messages() >= max).unwrap_or(false) ||
+            config.segment_age.map(|max| segment.age() >= max).unwrap_or(false)
+    }
+
+    // Seals the active segment; the next produce starts a new one
+    fn roll(&mut self) -> Result<()> {
+        self.sync()?;
+
+        if let Some(mut segment) = self.current_segment.take() {
+            segment.close()?;
+            self.segments.push(segment);
+        }
 
         Ok(())
     }
 
-    pub fn close(&mut self) {
+    pub fn config(&self) -> &TopicConfig {
+        &self.config
+    }
+
+    pub fn num_segments(&self) -> usize {
+        self.all_segments().count()
+    }
+
+    pub fn close(&mut self) -> Result<()> {
+        self.sync()?;
+
         if let Some(segment) = self.current_segment.as_mut() {
-            segment.close();
+            segment.close()?;
         }
+
+        Ok(())
+    }
+
+    fn all_segments(&self) -> impl Iterator<Item = &Segment> {
+        self.segments.iter().chain(self.current_segment.iter())
     }
 }